@@ -32,14 +32,16 @@ fn main() {
 
     let mut core = robus::init(BAUDRATE);
 
-    let button = core.create_module("fire_button", ModuleType::Button, &|_| {});
+    let button = core.create_module("fire_button", ModuleType::Button, |_| {});
     core.set_module_id(button, BUTTON_MODULE_ID);
     let pin = gpio::Input::setup(PIN);
 
     let mut msg = Message::id(LED_MODULE_ID, Command::PublishState, &vec![0]);
     loop {
+        core.poll();
         msg.data[0] = pin.read() as u8;
-        core.send(button, &mut msg);
+        // A lost state is sent again at the next period.
+        let _ = core.send(button, &mut msg);
 
         rcc::ms_delay(100);
     }
@@ -25,16 +25,15 @@ fn main() {
     #[cfg(target_arch = "arm")]
     hal::allocator::setup(HEAP_SIZE);
 
-    let pin = gpio::Output::setup(PIN);
-    let pin = core::cell::RefCell::new(pin);
+    let mut pin = gpio::Output::setup(PIN);
 
     let cb = move |msg: Message| {
         match msg.header.command {
             Command::PublishState => {
                 if msg.data[0] == 1 {
-                    pin.borrow_mut().high();
+                    pin.high();
                 } else {
-                    pin.borrow_mut().low();
+                    pin.low();
                 }
             }
             _ => (),
@@ -43,8 +42,10 @@ fn main() {
 
     let mut core = robus::init(BAUDRATE);
 
-    let led = core.create_module("disco_led", ModuleType::Ledstrip, &cb);
+    let led = core.create_module("disco_led", ModuleType::Ledstrip, cb);
     core.set_module_id(led, LED_MODULE_ID);
 
-    loop {}
+    loop {
+        core.poll();
+    }
 }
@@ -45,12 +45,13 @@ fn main() {
     // And then we send the first message to initiate the loop.
     if ID == 1 {
         hal::rcc::ms_delay(1000);
-        core.send(module, &mut send_msg);
+        core.send(module, &mut send_msg).unwrap();
     }
 
     loop {
+        core.poll();
         if let Some(_) = rx.recv() {
-            core.send(module, &mut send_msg);
+            core.send(module, &mut send_msg).unwrap();
         }
     }
 }
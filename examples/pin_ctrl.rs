@@ -19,15 +19,10 @@ const HEAP_SIZE: usize = 5000;
 
 #[macro_use(vec)]
 extern crate alloc;
-use alloc::{String, Vec};
+use alloc::Vec;
 
 use hal::{adc, gpio};
 
-fn ser_intro(alias: &str, mod_type: ModuleType) -> Vec<u8> {
-    let mut v = String::from(alias).into_bytes();
-    v.push(mod_type as u8);
-    v
-}
 struct State {
     pin1: adc::Analog,
     pin2: gpio::Output,
@@ -59,7 +54,6 @@ fn main() {
     hal::allocator::setup(HEAP_SIZE);
 
     // robus setup
-    let (tx, rx) = robus::message_queue();
     let mut core = robus::init(ROBUS_BAUDRATE);
 
     // Analog pins setup
@@ -89,48 +83,40 @@ fn main() {
         pin8,
         pin9,
     };
-    let m = core.create_module(ALIAS, TYPE, &|msg| {
-        tx.send(msg);
+    let m = core.create_module_with_reply(ALIAS, TYPE, move |msg| match msg.header.command {
+        Command::Identify => Some(Message::reply(
+            Command::Introduction,
+            &robus::introduction(ALIAS, TYPE),
+        )),
+        Command::GetState => Some(Message::reply(Command::PublishState, &pins.serialize())),
+        Command::SetState => {
+            // p2 value
+            if msg.data[0] == 1 {
+                pins.pin2.high();
+            }
+            if msg.data[0] == 0 {
+                pins.pin2.low();
+            }
+            // p3 value
+            if msg.data[1] == 1 {
+                pins.pin3.high();
+            }
+            if msg.data[1] == 0 {
+                pins.pin3.low();
+            }
+            // p4 value
+            if msg.data[2] == 1 {
+                pins.pin4.high();
+            }
+            if msg.data[2] == 0 {
+                pins.pin4.low();
+            }
+            None
+        }
+        _ => None,
     });
     core.set_module_id(m, ID);
     loop {
-        if let Some(msg) = rx.recv() {
-            match msg.header.command {
-                Command::Identify => {
-                    let data = ser_intro(ALIAS, TYPE);
-                    let mut answer = Message::id(msg.header.source, Command::Introduction, &data);
-                    core.send(m, &mut answer);
-                }
-                Command::GetState => {
-                    let mut answer =
-                        Message::id(msg.header.source, Command::PublishState, &pins.serialize());
-                    core.send(m, &mut answer);
-                }
-                Command::SetState => {
-                    // p2 value
-                    if msg.data[0] == 1 {
-                        pins.pin2.high();
-                    }
-                    if msg.data[0] == 0 {
-                        pins.pin2.low();
-                    }
-                    // p3 value
-                    if msg.data[1] == 1 {
-                        pins.pin3.high();
-                    }
-                    if msg.data[1] == 0 {
-                        pins.pin3.low();
-                    }
-                    // p4 value
-                    if msg.data[2] == 1 {
-                        pins.pin4.high();
-                    }
-                    if msg.data[2] == 0 {
-                        pins.pin4.low();
-                    }
-                }
-                _ => {}
-            }
-        }
+        core.poll();
     }
 }
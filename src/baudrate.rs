@@ -0,0 +1,238 @@
+//! Bus-wide baudrate switch
+//!
+//! The gate broadcasts `Command::SetBaudrate` with the new baudrate and the delay before the switch (see `Core::switch_baudrate`). Every `Core` applies it once the delay is over, then the gate confirms the switch at the new baudrate. A board which does not receive any valid frame in time goes back to the previous baudrate.
+
+use Message;
+
+use physical;
+
+use alloc::vec::Vec;
+
+/// Time left to the other boards to switch before the gate confirms (in µs).
+pub const SETTLE_DELAY: u32 = 10_000;
+/// Time given to the gate to confirm the switch (in µs).
+pub const CONFIRM_TIMEOUT: u32 = 200_000;
+
+/// Data of the `Command::SetBaudrate` announcing a switch.
+pub fn announce(baudrate: u32, delay: u16) -> Vec<u8> {
+    vec![
+        baudrate as u8,
+        (baudrate >> 8) as u8,
+        (baudrate >> 16) as u8,
+        (baudrate >> 24) as u8,
+        delay as u8,
+        (delay >> 8) as u8,
+    ]
+}
+
+/// Reads the baudrate and delay (in ms) of an announced switch.
+///
+/// Returns `None` if the data is invalid or if the UART can not run at the baudrate (see `physical::supports_baudrate`).
+pub fn parse_announce(data: &[u8]) -> Option<(u32, u16)> {
+    if data.len() != 6 {
+        return None;
+    }
+    let baudrate =
+        data[0] as u32 | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24;
+    let delay = data[4] as u16 | (data[5] as u16) << 8;
+    if !physical::supports_baudrate(baudrate) {
+        return None;
+    }
+    Some((baudrate, delay))
+}
+
+/// Data of the `Command::SetBaudrate` confirming a switch.
+pub fn confirmation(baudrate: u32) -> Vec<u8> {
+    announce(baudrate, 0)[..4].to_vec()
+}
+
+/// What the `Core` needs to do for its switch.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Wait,
+    /// Apply the new baudrate.
+    Apply(u32),
+    /// Confirm the new baudrate from the given bus id, the switch is over.
+    Confirm(u16, u32),
+    /// Go back to the previous baudrate, the switch is over.
+    Revert(u32),
+    /// The switch is over.
+    Done,
+}
+
+enum Step {
+    /// Waiting for the given delay (in µs) before applying the new baudrate.
+    Scheduled(u32),
+    Applied,
+}
+
+/// Baudrate switch in progress.
+pub struct Switch {
+    baudrate: u32,
+    previous: u32,
+    step: Step,
+    /// Start of the current step (in µs, see `physical::micros`).
+    since: u32,
+    /// Bus id confirming the switch (the gate), `None` on the other boards.
+    confirm_from: Option<u16>,
+    confirmed: bool,
+}
+
+impl Switch {
+    /// Schedules a switch from `previous` to `baudrate` in `delay` ms.
+    pub fn new(
+        baudrate: u32,
+        previous: u32,
+        delay: u16,
+        now: u32,
+        confirm_from: Option<u16>,
+    ) -> Switch {
+        Switch {
+            baudrate,
+            previous,
+            step: Step::Scheduled(delay as u32 * 1000),
+            since: now,
+            confirm_from,
+            confirmed: false,
+        }
+    }
+    /// A valid frame has been received.
+    pub fn confirm(&mut self) {
+        if let Step::Applied = self.step {
+            self.confirmed = true;
+        }
+    }
+    /// Returns what needs to be done at `now` (in µs).
+    pub fn update(&mut self, now: u32) -> Action {
+        let elapsed = now.wrapping_sub(self.since);
+        match self.step {
+            Step::Scheduled(delay) => {
+                if elapsed < delay {
+                    return Action::Wait;
+                }
+                self.step = Step::Applied;
+                self.since = now;
+                Action::Apply(self.baudrate)
+            }
+            Step::Applied => match self.confirm_from {
+                Some(id) if elapsed >= SETTLE_DELAY => Action::Confirm(id, self.baudrate),
+                Some(_) => Action::Wait,
+                None if self.confirmed => Action::Done,
+                None if elapsed >= CONFIRM_TIMEOUT => Action::Revert(self.previous),
+                None => Action::Wait,
+            },
+        }
+    }
+}
+
+/// Baudrate switches of a `Core`: its own ones and the ones announced by the gate.
+pub struct Switcher {
+    switch: Option<Switch>,
+}
+
+impl Switcher {
+    pub fn new() -> Switcher {
+        Switcher { switch: None }
+    }
+    /// Schedules a switch, replacing the one in progress (see `Switch::new`).
+    pub fn schedule(
+        &mut self,
+        baudrate: u32,
+        previous: u32,
+        delay: u16,
+        now: u32,
+        confirm_from: Option<u16>,
+    ) {
+        self.switch = Some(Switch::new(baudrate, previous, delay, now, confirm_from));
+    }
+    /// Handles a `Command::SetBaudrate` broadcast by another board at `now` (in µs), while running at `previous`.
+    ///
+    /// Confirmations and invalid announces (see `parse_announce`) are ignored.
+    pub fn handle(&mut self, msg: &Message, previous: u32, now: u32) {
+        if let Some((baudrate, delay)) = parse_announce(&msg.data) {
+            self.schedule(baudrate, previous, delay, now, None);
+        }
+    }
+    /// A valid frame has been received.
+    pub fn confirm(&mut self) {
+        if let Some(ref mut switch) = self.switch {
+            switch.confirm();
+        }
+    }
+    /// Returns what needs to be done at `now` (in µs), the switch is forgotten once over.
+    pub fn update(&mut self, now: u32) -> Action {
+        let action = match self.switch {
+            Some(ref mut switch) => switch.update(now),
+            None => return Action::Wait,
+        };
+        match action {
+            Action::Wait | Action::Apply(_) => {}
+            _ => self.switch = None,
+        }
+        action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use Command;
+
+    #[test]
+    fn payloads() {
+        let data = announce(1_000_000, 300);
+        assert_eq!(parse_announce(&data), Some((1_000_000, 300)));
+        assert_eq!(parse_announce(&confirmation(1_000_000)), None);
+        assert_eq!(confirmation(1_000_000), data[..4].to_vec());
+        // The UART can not run at these.
+        assert_eq!(parse_announce(&announce(0, 300)), None);
+        assert_eq!(parse_announce(&announce(1, 300)), None);
+        assert_eq!(parse_announce(&announce(1_000, 300)), None);
+        assert_eq!(parse_announce(&announce(10_000_000, 300)), None);
+    }
+    #[test]
+    fn confirmed_switch() {
+        let mut switch = Switch::new(115_200, 57_600, 5, 0, None);
+        // Early frames do not count.
+        switch.confirm();
+        assert_eq!(switch.update(4_999), Action::Wait);
+        assert_eq!(switch.update(5_000), Action::Apply(115_200));
+        assert_eq!(switch.update(6_000), Action::Wait);
+        switch.confirm();
+        assert_eq!(switch.update(7_000), Action::Done);
+
+        let mut gate = Switch::new(115_200, 57_600, 5, 0, Some(1));
+        assert_eq!(gate.update(5_000), Action::Apply(115_200));
+        assert_eq!(gate.update(5_000 + SETTLE_DELAY - 1), Action::Wait);
+        assert_eq!(gate.update(5_000 + SETTLE_DELAY), Action::Confirm(1, 115_200));
+    }
+    #[test]
+    fn fallback() {
+        // Across the wrap around of the clock
+        let start = u32::max_value() - 1_000;
+        let mut switch = Switch::new(115_200, 57_600, 5, start, None);
+        assert_eq!(switch.update(start.wrapping_add(5_000)), Action::Apply(115_200));
+        let applied = start.wrapping_add(5_000);
+        assert_eq!(switch.update(applied + CONFIRM_TIMEOUT - 1), Action::Wait);
+        assert_eq!(switch.update(applied + CONFIRM_TIMEOUT), Action::Revert(57_600));
+    }
+    #[test]
+    fn announced_switches() {
+        let mut switcher = Switcher::new();
+        let confirm = Message::broadcast(Command::SetBaudrate, &confirmation(115_200));
+        switcher.handle(&confirm, 57_600, 0);
+        let invalid = Message::broadcast(Command::SetBaudrate, &announce(1, 5));
+        switcher.handle(&invalid, 57_600, 0);
+        assert_eq!(switcher.update(10_000), Action::Wait);
+        assert!(switcher.switch.is_none());
+
+        let valid = Message::broadcast(Command::SetBaudrate, &announce(115_200, 5));
+        switcher.handle(&valid, 57_600, 0);
+        assert_eq!(switcher.update(5_000), Action::Apply(115_200));
+        assert_eq!(switcher.update(5_000 + CONFIRM_TIMEOUT), Action::Revert(57_600));
+        // Over
+        assert!(switcher.switch.is_none());
+        assert_eq!(switcher.update(u32::max_value()), Action::Wait);
+    }
+}
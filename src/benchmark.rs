@@ -0,0 +1,201 @@
+//! Bus throughput benchmark
+//!
+//! The gate announces a stream of `Command::DataResult` frames to a target with `Command::DataRate` (number of frames and data size), sends them, then asks for the target counts with an empty `Command::DataRate`. The target answers with `Command::DataRateResult` (see `Core::benchmark`).
+
+use {Command, Message};
+
+use msg::{CRC_SIZE, HEADER_SIZE};
+
+use alloc::vec::Vec;
+
+/// Smallest data size of a benchmark frame: the sequence number.
+pub const MIN_FRAME_DATA: usize = 2;
+
+/// Data of the `Command::DataRate` announcing a benchmark.
+pub fn announce(count: u16, size: u8) -> Vec<u8> {
+    vec![count as u8, (count >> 8) as u8, size]
+}
+
+/// Data of the `seq`-th `Command::DataResult` frame, of `size` bytes.
+pub fn frame(seq: u16, size: usize) -> Vec<u8> {
+    let mut data = vec![0; size];
+    data[0] = seq as u8;
+    data[1] = (seq >> 8) as u8;
+    data
+}
+
+/// Counts the benchmark frames received by the target.
+pub struct Receiver {
+    expected: u16,
+    received: u16,
+    /// Reception time of the first and last frames (in µs).
+    first: Option<u32>,
+    last: u32,
+}
+
+impl Receiver {
+    /// Starts counting for the benchmark announced by `data` (`None` if invalid).
+    pub fn start(data: &[u8]) -> Option<Receiver> {
+        if data.len() != 3 {
+            return None;
+        }
+        Some(Receiver {
+            expected: data[0] as u16 | (data[1] as u16) << 8,
+            received: 0,
+            first: None,
+            last: 0,
+        })
+    }
+    /// Counts a frame received at `now` (in µs).
+    pub fn count(&mut self, now: u32) {
+        self.received = self.received.saturating_add(1);
+        if self.first.is_none() {
+            self.first = Some(now);
+        }
+        self.last = now;
+    }
+    /// Data of the `Command::DataRateResult` answer: received and lost frames, elapsed µs between the first and last frames.
+    pub fn result(&self) -> Vec<u8> {
+        let lost = self.expected.saturating_sub(self.received);
+        let elapsed = match self.first {
+            Some(first) => self.last.wrapping_sub(first),
+            None => 0,
+        };
+        vec![
+            self.received as u8,
+            (self.received >> 8) as u8,
+            lost as u8,
+            (lost >> 8) as u8,
+            elapsed as u8,
+            (elapsed >> 8) as u8,
+            (elapsed >> 16) as u8,
+            (elapsed >> 24) as u8,
+        ]
+    }
+}
+
+/// Benchmarks targeting the modules of a `Core`, run by the `Core` itself.
+pub struct Counter {
+    receiver: Option<Receiver>,
+}
+
+impl Counter {
+    pub fn new() -> Counter {
+        Counter { receiver: None }
+    }
+    /// Handles a `Command::DataRate` or `Command::DataResult` received at `received` (in µs).
+    ///
+    /// Returns the `Command::DataRateResult` answer to the end of a benchmark, without its source.
+    pub fn handle(&mut self, msg: &Message, received: u32) -> Option<Message> {
+        match msg.header.command {
+            Command::DataRate if msg.data.is_empty() => self.receiver.take().map(|receiver| {
+                Message::id(msg.header.source, Command::DataRateResult, &receiver.result())
+            }),
+            Command::DataRate => {
+                self.receiver = Receiver::start(&msg.data);
+                None
+            }
+            Command::DataResult => {
+                if let Some(ref mut receiver) = self.receiver {
+                    receiver.count(received);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Result of a benchmark (see `Core::benchmark`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BenchmarkReport {
+    /// Frames sent by the gate.
+    pub sent: u16,
+    /// Frames received by the target.
+    pub received: u16,
+    /// Frames missed by the target.
+    pub lost: u16,
+    /// Time between the reception of the first and last frames (in µs).
+    pub elapsed: u32,
+    /// Size of each frame on the bus (in bytes).
+    pub frame_size: usize,
+}
+
+impl BenchmarkReport {
+    /// Reads the `Command::DataRateResult` answer of a benchmark of `sent` frames of `data_size` bytes.
+    pub fn from_answer(sent: u16, data_size: usize, data: &[u8]) -> Option<BenchmarkReport> {
+        if data.len() != 8 {
+            return None;
+        }
+        Some(BenchmarkReport {
+            sent,
+            received: data[0] as u16 | (data[1] as u16) << 8,
+            lost: data[2] as u16 | (data[3] as u16) << 8,
+            elapsed: data[4] as u32 | (data[5] as u32) << 8 | (data[6] as u32) << 16
+                | (data[7] as u32) << 24,
+            frame_size: HEADER_SIZE + data_size + CRC_SIZE,
+        })
+    }
+    /// Effective throughput (in bytes/s), 0 if less than two frames were received.
+    ///
+    /// The elapsed time covers the frames following the first one.
+    pub fn throughput(&self) -> u32 {
+        if self.received < 2 || self.elapsed == 0 {
+            return 0;
+        }
+        let bytes = (self.received - 1) as u64 * self.frame_size as u64;
+        (bytes * 1_000_000 / self.elapsed as u64) as u32
+    }
+    /// Ratio of lost frames (from 0 to 1).
+    pub fn loss(&self) -> f32 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        self.lost as f32 / self.sent as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receiver_result() {
+        let mut receiver = Receiver::start(&announce(10, 8)).unwrap();
+        receiver.count(1_000);
+        receiver.count(1_500);
+        receiver.count(3_000);
+
+        let report = BenchmarkReport::from_answer(10, 8, &receiver.result()).unwrap();
+        assert_eq!(report.received, 3);
+        assert_eq!(report.lost, 7);
+        assert_eq!(report.elapsed, 2_000);
+        assert_eq!(report.frame_size, HEADER_SIZE + 8 + CRC_SIZE);
+        // 2 frames of 16 bytes in 2ms
+        assert_eq!(report.throughput(), 16_000);
+        assert_eq!(report.loss(), 0.7);
+
+        assert!(Receiver::start(&[]).is_none());
+        assert!(BenchmarkReport::from_answer(10, 8, &[0; 4]).is_none());
+    }
+    #[test]
+    fn counter() {
+        let mut counter = Counter::new();
+        let end = Message::id(3, Command::DataRate, &vec![]);
+        // Nothing to answer without a benchmark.
+        assert_eq!(counter.handle(&end, 0), None);
+
+        let start = Message::id(3, Command::DataRate, &announce(2, 8));
+        assert_eq!(counter.handle(&start, 0), None);
+        let data = Message::id(3, Command::DataResult, &frame(0, 8));
+        assert_eq!(counter.handle(&data, 1_000), None);
+        assert_eq!(counter.handle(&data, 1_500), None);
+
+        let answer = counter.handle(&end, 2_000).unwrap();
+        assert_eq!(answer.header.command, Command::DataRateResult);
+        let report = BenchmarkReport::from_answer(2, 8, &answer.data).unwrap();
+        assert_eq!((report.received, report.lost, report.elapsed), (2, 0, 500));
+        // Answered once
+        assert_eq!(counter.handle(&end, 3_000), None);
+    }
+}
@@ -0,0 +1,255 @@
+//! Bus time
+//!
+//! Each `Core` reads its local time from a `TickSource`. The time master broadcasts its own time (`Command::TimeSync`) and answers the synchronization requests of the other boards, which estimate their offset from the round-trip of the request (see `Core::sync_time`).
+
+use Message;
+
+use module::ModuleHandle;
+use physical;
+
+use alloc::vec::Vec;
+
+/// Source of the local time of a `Core` (see `Core::set_tick_source`).
+///
+/// The default one reads the clock timer of the board (the host clock on the host). Tests may drive their own.
+pub trait TickSource {
+    /// Returns the microseconds elapsed since an arbitrary origin.
+    fn micros(&mut self) -> u64;
+}
+
+/// `TickSource` reading `physical::micros`.
+///
+/// The 32 bits timer wraps around after about 71 minutes: it needs to be read at least once per wrap to keep counting.
+pub struct BoardClock {
+    last: u32,
+    /// Elapsed time of the previous wraps.
+    high: u64,
+}
+
+impl BoardClock {
+    pub fn new() -> BoardClock {
+        BoardClock {
+            last: physical::micros(),
+            high: 0,
+        }
+    }
+    /// Extends a raw reading of the timer to 64 bits.
+    fn extend(&mut self, raw: u32) -> u64 {
+        if raw < self.last {
+            self.high += 1 << 32;
+        }
+        self.last = raw;
+        self.high | raw as u64
+    }
+}
+
+impl TickSource for BoardClock {
+    fn micros(&mut self) -> u64 {
+        let raw = physical::micros();
+        self.extend(raw)
+    }
+}
+
+/// Data of a timestamp (in µs).
+pub fn encode(time: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (time >> (8 * i)) as u8;
+    }
+    bytes
+}
+
+/// Reads the timestamps of the data of a `Command::TimeSync`.
+pub fn decode(data: &[u8]) -> Option<u64> {
+    if data.len() != 8 {
+        return None;
+    }
+    Some(
+        data.iter()
+            .enumerate()
+            .fold(0, |time, (i, byte)| time | (*byte as u64) << (8 * i)),
+    )
+}
+
+/// Offset of the master time and one-way delay (in µs) from a round-trip
+///
+/// * `t1`: sending of the request (local time)
+/// * `t2`: reception of the request (master time)
+/// * `t3`: sending of the answer (master time)
+/// * `t4`: reception of the answer (local time)
+///
+/// The delay is assumed to be the same both ways.
+pub fn round_trip(t1: u64, t2: u64, t3: u64, t4: u64) -> (i64, u64) {
+    // The master times come from the bus: they must not make the arithmetic overflow.
+    let offset = (t2.wrapping_sub(t1) as i64).wrapping_add(t3.wrapping_sub(t4) as i64) / 2;
+    let delay = t4.saturating_sub(t1).saturating_sub(t3.saturating_sub(t2)) / 2;
+    (offset, delay)
+}
+
+/// Bus time of a `Core`: the one it broadcasts as the time master, or the one of the master it follows.
+pub struct TimeKeeper {
+    /// Module broadcasting our time, with the period and time of the last broadcast (in µs).
+    master: Option<(ModuleHandle, u64, u64)>,
+    /// Offset of the bus time from our local time (in µs).
+    offset: i64,
+    /// One-way delay of the frames measured by `sync` (in µs).
+    delay: u64,
+    /// Bus id of the time master we synchronized with.
+    source: Option<u16>,
+}
+
+impl TimeKeeper {
+    pub fn new() -> TimeKeeper {
+        TimeKeeper {
+            master: None,
+            offset: 0,
+            delay: 0,
+            source: None,
+        }
+    }
+    /// Our local time becomes the bus time, broadcast by `sender` every `period` ms from `now` (in µs).
+    pub fn set_master(&mut self, sender: ModuleHandle, period: u32, now: u64) {
+        let period = period as u64 * 1000;
+        // The first broadcast is sent right away.
+        self.master = Some((sender, period, now.wrapping_sub(period)));
+        self.offset = 0;
+        self.delay = 0;
+        self.source = None;
+    }
+    /// Returns the module broadcasting our time (`None` if we are not the master).
+    pub fn master(&self) -> Option<ModuleHandle> {
+        self.master.map(|(sender, _, _)| sender)
+    }
+    /// Stop broadcasting our time.
+    pub fn stop(&mut self) {
+        self.master = None;
+    }
+    /// Returns the module broadcasting our time if a broadcast is due at `now` (in µs).
+    pub fn due(&mut self, now: u64) -> Option<ModuleHandle> {
+        match self.master {
+            Some((sender, period, ref mut last)) if now.wrapping_sub(*last) >= period => {
+                *last = now;
+                Some(sender)
+            }
+            _ => None,
+        }
+    }
+    /// Follow the time master `master` from the answer to our `Command::TimeSync` request
+    ///
+    /// * `sent`: sending of the request (in µs)
+    /// * `answer`: data of the answer
+    /// * `received`: reception of the answer (in µs)
+    ///
+    /// Returns the estimated offset, `None` if the answer is malformed.
+    pub fn sync(&mut self, master: u16, sent: u64, answer: &[u8], received: u64) -> Option<i64> {
+        if answer.len() != 16 {
+            return None;
+        }
+        let (t2, t3) = match (decode(&answer[..8]), decode(&answer[8..])) {
+            (Some(t2), Some(t3)) => (t2, t3),
+            _ => return None,
+        };
+        let (offset, delay) = round_trip(sent, t2, t3, received);
+        self.offset = offset;
+        self.delay = delay;
+        self.source = Some(master);
+        Some(offset)
+    }
+    /// Handles a `Command::TimeSync` broadcast received at `received` (in µs).
+    ///
+    /// Only the master we synchronized with is followed, a master follows nobody.
+    pub fn handle(&mut self, msg: &Message, received: u64) {
+        if self.master.is_some() || self.source != Some(msg.header.source) {
+            return;
+        }
+        // The master time was read a frame duration ago.
+        if let Some(master) = decode(&msg.data) {
+            self.offset = master as i64 + self.delay as i64 - received as i64;
+        }
+    }
+    /// Returns the bus time at the local time `now` (in µs).
+    pub fn bus_time(&self, now: u64) -> u64 {
+        (now as i64 + self.offset) as u64
+    }
+}
+
+/// Data of the answer of the master to a `Command::TimeSync` request received at `received` and answered at `now` (in µs).
+pub fn answer(received: u64, now: u64) -> Vec<u8> {
+    let mut data = encode(received).to_vec();
+    data.extend_from_slice(&encode(now));
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use {Command, Module};
+    use module::Registry;
+    use module::tests::rand_type;
+
+    #[test]
+    fn timestamps() {
+        let time = 0x0123_4567_89AB_CDEF;
+        assert_eq!(decode(&encode(time)), Some(time));
+        assert_eq!(decode(&[1, 2]), None);
+    }
+    #[test]
+    fn offset_estimation() {
+        // Master 1000µs ahead, 100µs each way, 50µs to answer.
+        let (offset, delay) = round_trip(10_000, 11_100, 11_150, 10_250);
+        assert_eq!(offset, 1_000);
+        assert_eq!(delay, 100);
+
+        // Master late
+        let (offset, _) = round_trip(10_000, 5_100, 5_100, 10_200);
+        assert_eq!(offset, -5_000);
+
+        // Bogus master times
+        let (_, delay) = round_trip(10_000, u64::max_value(), 0, 10_200);
+        assert_eq!(delay, 100);
+        let (_, delay) = round_trip(10_000, 0, u64::max_value(), 10_200);
+        assert_eq!(delay, 0);
+    }
+    #[test]
+    fn followed_master() {
+        let broadcast = |source: u16, time: u64| {
+            let mut msg = Message::broadcast(Command::TimeSync, &encode(time).to_vec());
+            msg.header.source = source;
+            msg
+        };
+        let mut keeper = TimeKeeper::new();
+
+        // Not synchronized yet
+        keeper.handle(&broadcast(1, 50_000), 10_000);
+        assert_eq!(keeper.bus_time(10_000), 10_000);
+
+        assert_eq!(keeper.sync(1, 10_000, &[0; 4], 10_200), None);
+        // Master 1000µs ahead, 100µs each way
+        let answer = answer(11_100, 11_100);
+        assert_eq!(keeper.sync(1, 10_000, &answer, 10_200), Some(1_000));
+        assert_eq!(keeper.bus_time(20_000), 21_000);
+
+        keeper.handle(&broadcast(3, 90_000), 20_000);
+        assert_eq!(keeper.bus_time(20_000), 21_000);
+        keeper.handle(&broadcast(1, 30_000), 20_000);
+        assert_eq!(keeper.bus_time(20_000), 30_100);
+
+        // A master keeps its own time.
+        let mut master = TimeKeeper::new();
+        let sender = Registry::new().add(Module::new("m1", rand_type(), |_| {}));
+        master.set_master(sender, 5, 0);
+        master.handle(&broadcast(1, 90_000), 1_000);
+        assert_eq!(master.bus_time(1_000), 1_000);
+        assert_eq!(master.due(0), Some(sender));
+        assert_eq!(master.due(4_999), None);
+        assert_eq!(master.due(5_000), Some(sender));
+    }
+    #[test]
+    fn clock_wrap() {
+        let mut clock = BoardClock { last: 0, high: 0 };
+        assert_eq!(clock.extend(u32::max_value()), u32::max_value() as u64);
+        assert_eq!(clock.extend(5), (1 << 32) + 5);
+        assert_eq!(clock.extend(10), (1 << 32) + 10);
+    }
+}
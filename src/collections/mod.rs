@@ -1,3 +1,5 @@
 mod msg_channel;
+mod priority;
 
 pub use self::msg_channel::message_queue;
+pub use self::priority::Priority;
@@ -0,0 +1,14 @@
+/// Priority of a queued `Message` (see `Core::enqueue`)
+///
+/// Frames of a higher priority are always sent before the ones of a lower priority (but the one being sent), whatever their order of arrival.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Safety related messages (e.g. `Command::StepperStop`).
+    Emergency = 0,
+    /// Control messages (e.g. `Command::SetCompliant`).
+    High,
+    /// Default priority.
+    Normal,
+    /// Streams of data (e.g. `Command::PublishState`), only allowed half of the queue so they can not starve the other priorities.
+    Bulk,
+}
@@ -1,3 +1,5 @@
+use core::mem;
+
 /// Internal Protocol Command
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -9,12 +11,57 @@ enum ProtocolCommand {
     GetStatus,
     GetFirmRevision,
     GetComRevision,
+    Ack,
+    ResetDetection,
+    WatchPtp,
+    PokeNext,
+    ModuleRemoved,
+    Fragment,
+    Heartbeat,
+    TimeSync,
+    _ProtocolEnd,
     _OffsetNumber = 30,
 }
 
 /// Available Command for `Message`
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Command {
+    /// Protocol commands
+    /// *They are handled by the `Core` itself and never reach the modules callbacks.*
+    /// *Requests carry no data while answers use the same command and always carry some.*
+
+    /// Gate asks the poked module how many ids it needs - answer size = 1 (number of ids)
+    GetId = ProtocolCommand::GetId as isize,
+    /// Gate gives its first id to the poked module - size = 2 (id)
+    WriteId,
+    /// Gate writes the alias of a module
+    WriteAlias,
+    /// Gate asks a module its type - answer size = 1 (`ModuleType`)
+    GetModuleType,
+    /// Gate asks a module its status - answer size = 2 (status word)
+    GetStatus,
+    /// Gate asks a module its firmware revision - answer size = revision string length
+    GetFirmRevision,
+    /// Gate asks a module its communication protocol revision - answer size = 1 (protocol version)
+    GetComRevision,
+    /// Module acknowledges the reception of a `TargetMode::IdAck` message - size = 1 (acknowledged command)
+    Ack,
+    /// Gate asks all modules to forget their id and topology
+    ResetDetection,
+    /// A module is poking one of its PTP lines, undetected modules need to check theirs
+    WatchPtp,
+    /// Gate asks a module to poke its next branches - answer size = 1 (answering branch, 255 once all the branches have been explored)
+    PokeNext,
+    /// A module announces it is leaving the bus (sent from its id)
+    ModuleRemoved,
+    /// Part of a transfer larger than a frame (see `Core::send_large`) - size = 4 (command, transfer, index, count) + chunk
+    Fragment,
+    /// A board tells the other ones it is alive (see `Core::set_heartbeat`) - size = 2 per module (ids of its modules)
+    Heartbeat,
+    /// Time master broadcasts its time - size = 8 (µs), or a module asks for it - answer size = 16 (reception and answer times, see `Core::sync_time`)
+    TimeSync,
+    _ProtocolEnd,
+
     /// Gate asks a module to identify itself
     Identify = ProtocolCommand::_OffsetNumber as isize,
     /// Module sends its alias and type to the gate
@@ -69,6 +116,47 @@ pub enum Command {
     _GateProtocolOffsetNumber,
 }
 
+impl Command {
+    /// Checks if the command belongs to the internal protocol (handled by the `Core` itself).
+    pub fn is_protocol(&self) -> bool {
+        (*self as u8) < Command::_ProtocolEnd as u8
+    }
+    /// Returns the command of the answer expected for a request (`None` if no answer is expected).
+    pub fn expected_reply(&self) -> Option<Command> {
+        match *self {
+            Command::GetId
+            | Command::WriteId
+            | Command::GetModuleType
+            | Command::GetStatus
+            | Command::GetFirmRevision
+            | Command::GetComRevision
+            | Command::PokeNext
+            | Command::TimeSync => Some(*self),
+            Command::Identify => Some(Command::Introduction),
+            Command::GetState => Some(Command::PublishState),
+            Command::DataRate => Some(Command::DataRateResult),
+            _ => None,
+        }
+    }
+}
+
+/// Checks if a raw value designates an existing `Command`.
+///
+/// Offset markers are not considered as valid commands.
+pub fn is_valid(value: u8) -> bool {
+    value < Command::_ProtocolEnd as u8
+        || (value >= Command::Identify as u8 && value < Command::_GateProtocolOffsetNumber as u8)
+}
+
+/// Converts a raw value into its `Command` if it is a valid one.
+pub fn from_u8(value: u8) -> Option<Command> {
+    if is_valid(value) {
+        Some(unsafe { mem::transmute::<u8, Command>(value) })
+    } else {
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -80,4 +168,42 @@ mod tests {
             ProtocolCommand::_OffsetNumber as u8
         );
     }
+    #[test]
+    fn protocol_commands() {
+        assert_eq!(Command::GetId as u8, ProtocolCommand::GetId as u8);
+        assert_eq!(Command::WriteAlias as u8, ProtocolCommand::WriteAlias as u8);
+        assert_eq!(Command::GetComRevision as u8, ProtocolCommand::GetComRevision as u8);
+        assert_eq!(Command::Ack as u8, ProtocolCommand::Ack as u8);
+        assert_eq!(Command::PokeNext as u8, ProtocolCommand::PokeNext as u8);
+        assert_eq!(Command::ModuleRemoved as u8, ProtocolCommand::ModuleRemoved as u8);
+        assert_eq!(Command::Fragment as u8, ProtocolCommand::Fragment as u8);
+        assert_eq!(Command::Heartbeat as u8, ProtocolCommand::Heartbeat as u8);
+        assert_eq!(Command::TimeSync as u8, ProtocolCommand::TimeSync as u8);
+        assert_eq!(Command::_ProtocolEnd as u8, ProtocolCommand::_ProtocolEnd as u8);
+
+        assert!(Command::GetId.is_protocol());
+        assert!(Command::Ack.is_protocol());
+        assert!(!Command::Identify.is_protocol());
+        assert!(!Command::PublishState.is_protocol());
+    }
+    #[test]
+    fn expected_replies() {
+        assert_eq!(Command::GetState.expected_reply(), Some(Command::PublishState));
+        assert_eq!(Command::Identify.expected_reply(), Some(Command::Introduction));
+        assert_eq!(Command::GetStatus.expected_reply(), Some(Command::GetStatus));
+        assert_eq!(Command::LedColor.expected_reply(), None);
+        assert_eq!(Command::Ack.expected_reply(), None);
+    }
+    #[test]
+    fn raw_values() {
+        assert_eq!(from_u8(Command::GetId as u8), Some(Command::GetId));
+        assert_eq!(from_u8(Command::Ack as u8), Some(Command::Ack));
+        assert_eq!(from_u8(Command::Identify as u8), Some(Command::Identify));
+        assert_eq!(from_u8(Command::SetState as u8), Some(Command::SetState));
+
+        assert_eq!(from_u8(Command::_ProtocolEnd as u8), None);
+        assert_eq!(from_u8(Command::Identify as u8 - 1), None);
+        assert_eq!(from_u8(Command::_GateProtocolOffsetNumber as u8), None);
+        assert_eq!(from_u8(255), None);
+    }
 }
@@ -0,0 +1,233 @@
+//! Network directory
+//!
+//! Modules introduce themselves with their alias and type (`Command::Introduction`) when asked to identify (`Command::Identify`). The gate broadcasts the request and gathers the answers into a `Directory` (see `Core::discover`).
+
+use {Message, ModuleType};
+use module::MAX_ALIAS_SIZE;
+use msg::TargetMode;
+
+use core::{slice, str};
+use alloc::String;
+use alloc::vec::Vec;
+
+/// Data of a `Command::Introduction`: the alias of the module followed by its type.
+///
+/// ## Examples
+/// ```
+/// use robus::{Command, Message, ModuleType};
+///
+/// let cb = |msg: Message| match msg.header.command {
+///     Command::Identify => Some(Message::reply(
+///         Command::Introduction,
+///         &robus::introduction("fire_button", ModuleType::Button),
+///     )),
+///     _ => None,
+/// };
+/// ```
+pub fn introduction(alias: &str, mod_type: ModuleType) -> Vec<u8> {
+    let mut data = String::from(alias).into_bytes();
+    data.push(mod_type as u8);
+    data
+}
+
+/// Reads the alias and type of a `Command::Introduction` (`None` if invalid).
+pub fn parse_introduction(data: &[u8]) -> Option<(String, ModuleType)> {
+    let (mod_type, alias) = match data.split_last() {
+        Some((mod_type, alias)) => match ModuleType::from_u8(*mod_type) {
+            Some(mod_type) => (mod_type, alias),
+            None => return None,
+        },
+        None => return None,
+    };
+    if alias.is_empty() || alias.len() > MAX_ALIAS_SIZE {
+        return None;
+    }
+    match str::from_utf8(alias) {
+        Ok(alias) => Some((String::from(alias), mod_type)),
+        Err(_) => None,
+    }
+}
+
+/// Module listed in a `Directory`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleInfo {
+    /// Bus id of the module.
+    pub id: u16,
+    pub alias: String,
+    pub mod_type: ModuleType,
+}
+
+/// Modules discovered on the bus, sorted by id.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Directory {
+    modules: Vec<ModuleInfo>,
+}
+
+impl Directory {
+    pub fn new() -> Directory {
+        Directory {
+            modules: Vec::new(),
+        }
+    }
+    /// Adds a module, it replaces the one with the same id if any.
+    pub fn insert(&mut self, info: ModuleInfo) {
+        match self.modules.binary_search_by_key(&info.id, |module| module.id) {
+            Ok(pos) => self.modules[pos] = info,
+            Err(pos) => self.modules.insert(pos, info),
+        }
+    }
+    /// Adds the module `id` from the data of its `Command::Introduction`.
+    ///
+    /// Returns `false` if the introduction is invalid, the directory is left untouched then.
+    pub fn introduce(&mut self, id: u16, data: &[u8]) -> bool {
+        match parse_introduction(data) {
+            Some((alias, mod_type)) => {
+                self.insert(ModuleInfo {
+                    id,
+                    alias,
+                    mod_type,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+    /// Returns the module with the given id.
+    pub fn get(&self, id: u16) -> Option<&ModuleInfo> {
+        self.modules.iter().find(|module| module.id == id)
+    }
+    /// Returns the first module with the given alias.
+    ///
+    /// Aliases are not guaranteed to be unique on the bus.
+    pub fn find(&self, alias: &str) -> Option<&ModuleInfo> {
+        self.modules.iter().find(|module| module.alias == alias)
+    }
+    /// Returns the modules of the given type.
+    pub fn of_type(&self, mod_type: ModuleType) -> Vec<&ModuleInfo> {
+        self.modules
+            .iter()
+            .filter(|module| module.mod_type == mod_type)
+            .collect()
+    }
+    pub fn iter(&self) -> slice::Iter<ModuleInfo> {
+        self.modules.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+}
+
+/// Directory filled by the `Command::Introduction` answers during a `Core::discover`.
+pub struct Discovery {
+    /// Bus id of the requesting module, with the modules gathered so far.
+    pending: Option<(u16, Directory)>,
+}
+
+impl Discovery {
+    pub fn new() -> Discovery {
+        Discovery { pending: None }
+    }
+    /// Starts gathering the introductions sent to our module `id`.
+    pub fn start(&mut self, id: u16) {
+        self.pending = Some((id, Directory::new()));
+    }
+    /// Adds an introduction answering the discovery.
+    ///
+    /// Returns `false` if no discovery is in progress or if the introduction is for another module. Invalid introductions are dropped.
+    pub fn collect(&mut self, msg: &Message) -> bool {
+        match self.pending {
+            Some((id, ref mut directory))
+                if msg.header.target_mode == TargetMode::Id && msg.header.target == id =>
+            {
+                directory.introduce(msg.header.source, &msg.data);
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Ends the discovery and returns the modules gathered.
+    pub fn finish(&mut self) -> Directory {
+        match self.pending.take() {
+            Some((_, directory)) => directory,
+            None => Directory::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use Command;
+
+    #[test]
+    fn introduction_payload() {
+        let data = introduction("fire_button", ModuleType::Button);
+        assert_eq!(data.len(), "fire_button".len() + 1);
+        assert_eq!(
+            parse_introduction(&data),
+            Some((String::from("fire_button"), ModuleType::Button))
+        );
+
+        assert_eq!(parse_introduction(&[]), None);
+        // No alias
+        assert_eq!(parse_introduction(&[ModuleType::Button as u8]), None);
+        // Unknown type
+        assert_eq!(parse_introduction(&[b'm', 0xFF]), None);
+        // Invalid utf-8
+        assert_eq!(parse_introduction(&[0xC3, ModuleType::Button as u8]), None);
+        assert_eq!(ModuleType::from_u8(ModuleType::Handy as u8), Some(ModuleType::Handy));
+    }
+    #[test]
+    fn lookups() {
+        let mut directory = Directory::new();
+        assert!(directory.introduce(5, &introduction("servo_1", ModuleType::Servo)));
+        assert!(directory.introduce(2, &introduction("button", ModuleType::Button)));
+        assert!(directory.introduce(3, &introduction("servo_2", ModuleType::Servo)));
+        assert!(!directory.introduce(4, &[]));
+        assert_eq!(directory.len(), 3);
+
+        // Sorted by id
+        let ids: Vec<u16> = directory.iter().map(|module| module.id).collect();
+        assert_eq!(ids, vec![2, 3, 5]);
+
+        assert_eq!(directory.get(2).unwrap().alias, "button");
+        assert_eq!(directory.get(4), None);
+        assert_eq!(directory.find("servo_2").unwrap().id, 3);
+        assert_eq!(directory.find("led"), None);
+        let servos: Vec<u16> = directory
+            .of_type(ModuleType::Servo)
+            .iter()
+            .map(|module| module.id)
+            .collect();
+        assert_eq!(servos, vec![3, 5]);
+
+        // Renamed module
+        directory.introduce(5, &introduction("arm", ModuleType::Servo));
+        assert_eq!(directory.len(), 3);
+        assert_eq!(directory.get(5).unwrap().alias, "arm");
+    }
+    #[test]
+    fn discovery() {
+        let mut discovery = Discovery::new();
+        let led = introduction("led", ModuleType::RgbLed);
+        let mut answer = Message::id(1, Command::Introduction, &led);
+        answer.header.source = 4;
+        assert!(!discovery.collect(&answer));
+
+        discovery.start(1);
+        assert!(discovery.collect(&answer));
+        // Sent to another module
+        answer.header.target = 2;
+        assert!(!discovery.collect(&answer));
+
+        let directory = discovery.finish();
+        assert_eq!(directory.len(), 1);
+        assert_eq!(directory.get(4).unwrap().alias, "led");
+        assert!(!discovery.collect(&answer));
+        assert!(discovery.finish().is_empty());
+    }
+}
@@ -11,7 +11,7 @@
 #![no_std]
 #![feature(alloc)]
 
-#[macro_use(format)]
+#[macro_use(format, vec)]
 extern crate alloc;
 
 #[cfg(target_arch = "arm")]
@@ -27,21 +27,52 @@ extern crate stm32f0x2 as ll;
 #[macro_use(print)]
 extern crate std;
 
+mod baudrate;
+mod benchmark;
+mod clock;
 mod command;
 mod collections;
+mod directory;
 mod error;
 mod module;
 mod msg;
 mod physical;
+mod presence;
 mod recv_buf;
 mod robus_core;
+#[cfg(not(target_arch = "arm"))]
+mod sim_bus;
+mod stats;
+mod storage;
+mod topology;
+mod transfer;
 
+pub use benchmark::BenchmarkReport;
+pub use clock::{BoardClock, TickSource};
 pub use command::Command;
-pub use collections::message_queue;
-pub use module::{Module, ModuleType};
-pub use msg::Message;
-pub use robus_core::Core;
+pub use collections::{message_queue, Priority};
+pub use directory::{introduction, parse_introduction, Directory, ModuleInfo};
+pub use module::{Module, ModuleHandle, ModuleType};
+pub use msg::{Message, ParsingError};
+pub use physical::{Port, RxEvent, UartPort, TX_QUEUE_SIZE};
+pub use presence::Presence;
+pub use robus_core::{BusError, Core, DeliveryError, RequestError, SendError};
+#[cfg(not(target_arch = "arm"))]
+pub use sim_bus::{SimBus, SimPort, SIM_BAUDRATE};
+pub use stats::{FrameCounters, Stats};
+pub use storage::{AliasStorage, MemoryStorage};
+#[cfg(not(target_arch = "arm"))]
+pub use storage::FileStorage;
+#[cfg(target_arch = "arm")]
+pub use physical::FlashStorage;
+pub use topology::{detect_topology, DetectionError, Network, Node, Ptp, PtpLine, Topology};
+#[cfg(not(target_arch = "arm"))]
+pub use topology::sim::{ptp_wire, SimLine};
+pub use transfer::{TransferError, MAX_TRANSFER_SIZE};
 
+/// Change the baudrate of this board only
+///
+/// The other boards keep their baudrate, use `Core::switch_baudrate` to change the baudrate of the whole bus.
 pub fn set_baudrate(robus_baudrate: u32) {
     physical::set_baudrate(robus_baudrate);
 }
@@ -49,12 +80,16 @@ pub fn set_baudrate(robus_baudrate: u32) {
 /// Init function to setup robus communication
 ///
 /// Must be called before actually trying to read or send any `Message`.
-pub fn init(robus_baudrate: u32) -> Core {
+///
+/// The received messages are dispatched by `Core::poll` which needs to be called regularly (e.g. in the main loop).
+pub fn init<'a>(robus_baudrate: u32) -> Core<'a> {
     let mut core = Core::new();
+    core.set_ptp_lines(physical::ptp_lines());
 
-    physical::setup(robus_baudrate, |byte| core.receive(byte));
+    physical::setup(robus_baudrate);
     physical::enable_interrupt();
     physical::setup_timeout();
+    physical::setup_clock();
 
     core
 }
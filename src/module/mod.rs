@@ -1,53 +1,58 @@
 mod mod_type;
 pub use self::mod_type::ModuleType;
+mod registry;
+pub use self::registry::{ModuleHandle, Registry};
 
 use Message;
+use msg::{Header, ParsingError, TargetMode, MAX_ID_VAL};
 
-const MAX_ALIAS_SIZE: usize = 15;
-const DEFAULT_ID: u16 = 0;
+use alloc::String;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+pub const MAX_ALIAS_SIZE: usize = 15;
+/// Id of a module which has not been given one yet.
+pub const DEFAULT_ID: u16 = 0;
 
 /// Robus Module struct used for representing actuators and sensors
 ///
 /// ## Examples
 /// ```
-/// use robus::{Command, Message};
-///
-/// let (tx, _) = robus::message_queue();
+/// use robus::{Command, Message, ModuleType};
 ///
-/// let cb = move |msg: Message| {
-///     let answer = match msg.header.command {
-///         Command::Identify => Some(Message::id(
-///             1,
-///             Command::Introduction,
-///             &"hello".as_bytes().to_owned(),
-///         )),
-///         Command::GetState => Some(Message::id(
-///             1,
-///             Command::PublishState,
-///             &vec![42],
-///         )),
-///         _ => None,
-///     };
-///     if let Some(answer) = answer {
-///         tx.send(answer);
-///     }
+/// // The answers are sent back to the source of the request.
+/// let cb = |msg: Message| match msg.header.command {
+///     Command::Identify => Some(Message::reply(
+///         Command::Introduction,
+///         &robus::introduction("fire_button", ModuleType::Button),
+///     )),
+///     Command::GetState => Some(Message::reply(Command::PublishState, &vec![42])),
+///     _ => None,
 /// };
 ///
-/// let mod_id = robus::Module::new(
+/// let module = robus::Module::with_reply(
 ///        "fire_button",
 ///        robus::ModuleType::Button,
-///        &cb,
+///        cb,
 ///    );
 /// ```
 pub struct Module<'a> {
     /// Each module have a unique name allowing to users to manage them easily.
-    pub alias: &'a str,
+    pub alias: String,
     /// A `ModuleType` defining the hardware category of the module.
     pub mod_type: ModuleType,
     /// The unique id of the module needed to send/receive specific messages.
     pub id: u16,
-    /// This callback is called on message reception for this module.
-    pub callback: &'a Fn(Message),
+    /// The multicast groups the module belongs to.
+    pub groups: Vec<u16>,
+    /// The status word of the module, sent to the gate on request.
+    pub status: u16,
+    /// Whether the module receives the messages it sends itself (off by default).
+    pub echo: bool,
+    /// This callback is called on message reception for this module, it may return a reply.
+    pub callback: Box<FnMut(Message) -> Option<Message> + 'a>,
+    /// This callback is called for each frame rejected by the reception (only used by sniffers).
+    pub error_callback: Option<Box<FnMut(ParsingError) + 'a>>,
 }
 
 impl<'a> Module<'a> {
@@ -57,16 +62,87 @@ impl<'a> Module<'a> {
     ///
     /// * `alias` - A `&str` containing the module name (max length is 15).
     /// * `mod_type` - A `ModuleType` struct designating the hardware category of the module.
-    /// * `cb` - A `FnMut(&Message)` containing the function to call at message reception.
-    pub fn new(alias: &'a str, mod_type: ModuleType, callback: &'a Fn(Message)) -> Module<'a> {
+    /// * `callback` - A `FnMut(Message)` containing the function to call at message reception. It may keep its own state and borrow data living as long as the module.
+    pub fn new<F>(alias: &str, mod_type: ModuleType, mut callback: F) -> Module<'a>
+    where
+        F: FnMut(Message) + 'a,
+    {
+        Module::with_reply(alias, mod_type, move |msg| {
+            callback(msg);
+            None
+        })
+    }
+    /// Creates a new a Module answering the messages it receives.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - A `&str` containing the module name (max length is 15).
+    /// * `mod_type` - A `ModuleType` struct designating the hardware category of the module.
+    /// * `callback` - A `FnMut(Message) -> Option<Message>` containing the function to call at message reception. The returned `Message` is sent back to the source of the received one (see `Message::reply`).
+    pub fn with_reply<F>(alias: &str, mod_type: ModuleType, callback: F) -> Module<'a>
+    where
+        F: FnMut(Message) -> Option<Message> + 'a,
+    {
         if alias.len() > MAX_ALIAS_SIZE {
             panic!("alias size({}) out of range.", alias.len());
         }
         Module {
-            alias,
+            alias: String::from(alias),
             id: DEFAULT_ID,
+            groups: Vec::new(),
+            status: 0,
+            echo: false,
             mod_type,
-            callback,
+            callback: Box::new(callback),
+            error_callback: None,
+        }
+    }
+    /// Renames the module.
+    ///
+    /// Returns `false` (and keeps the previous alias) if the alias is empty, too long (max length is 15) or contains control characters (e.g. line breaks).
+    pub fn set_alias(&mut self, alias: &str) -> bool {
+        if alias.is_empty() || alias.len() > MAX_ALIAS_SIZE
+            || alias.chars().any(|c| c.is_control())
+        {
+            return false;
+        }
+        self.alias = String::from(alias);
+        true
+    }
+    /// Adds the module to a multicast group.
+    ///
+    /// Joining a group the module already belongs to has no effect.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - A u16 designating the multicast id of the group (max value is actually a u12).
+    pub fn join_group(&mut self, group: u16) {
+        if group > MAX_ID_VAL {
+            panic!("group id overflow.");
+        }
+        if !self.is_member(group) {
+            self.groups.push(group);
+        }
+    }
+    /// Removes the module from a multicast group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - A u16 designating the multicast id of the group.
+    pub fn leave_group(&mut self, group: u16) {
+        self.groups.retain(|g| *g != group);
+    }
+    /// Checks if the module belongs to a multicast group.
+    pub fn is_member(&self, group: u16) -> bool {
+        self.groups.contains(&group)
+    }
+    /// Checks if the module is targeted by a message.
+    pub fn is_target(&self, header: &Header) -> bool {
+        match header.target_mode {
+            TargetMode::Id | TargetMode::IdAck => self.id == header.target,
+            TargetMode::Type => self.mod_type as u16 == header.target,
+            TargetMode::Broadcast => true,
+            TargetMode::Multicast => self.is_member(header.target),
         }
     }
 }
@@ -78,6 +154,7 @@ pub mod tests {
     use self::std::string::String;
 
     use super::*;
+    use Command;
 
     extern crate rand;
     use self::rand::{thread_rng, Rng};
@@ -87,11 +164,77 @@ pub mod tests {
         let alias = rand_alias();
         let mod_type = rand_type();
 
-        let module = Module::new(&alias, mod_type, &|_| {});
+        let module = Module::new(&alias, mod_type, |_| {});
 
         assert_eq!(module.alias, alias);
         assert_eq!(module.id, DEFAULT_ID);
         assert_eq!(module.mod_type, mod_type);
+        assert!(module.groups.is_empty());
+    }
+
+    #[test]
+    fn rename() {
+        let mut module = Module::new("m", rand_type(), |_| {});
+
+        let alias = rand_alias();
+        assert!(module.set_alias(&alias));
+        assert_eq!(module.alias, alias);
+
+        assert!(!module.set_alias(""));
+        assert!(!module.set_alias("a_much_too_long_alias"));
+        assert!(!module.set_alias("left\nleg"));
+        assert_eq!(module.alias, alias);
+    }
+
+    #[test]
+    fn join_and_leave_groups() {
+        let mut module = Module::new("m", rand_type(), |_| {});
+
+        module.join_group(1);
+        module.join_group(42);
+        module.join_group(42);
+
+        assert_eq!(module.groups, vec![1, 42]);
+        assert!(module.is_member(1));
+        assert!(module.is_member(42));
+        assert!(!module.is_member(2));
+
+        module.leave_group(1);
+        module.leave_group(2);
+
+        assert_eq!(module.groups, vec![42]);
+        assert!(!module.is_member(1));
+    }
+
+    #[test]
+    fn targeting() {
+        let mut module = Module::new("m", ModuleType::Servo, |_| {});
+        module.id = 3;
+        module.join_group(7);
+
+        assert!(module.is_target(&Message::id(3, Command::GetState, &vec![]).header));
+        assert!(module.is_target(&Message::id_ack(3, Command::GetState, &vec![]).header));
+        assert!(!module.is_target(&Message::id(4, Command::GetState, &vec![]).header));
+        assert!(module.is_target(&Message::type_msg(
+            ModuleType::Servo as u16,
+            Command::GetState,
+            &vec![]
+        ).header));
+        assert!(!module.is_target(&Message::type_msg(
+            ModuleType::Button as u16,
+            Command::GetState,
+            &vec![]
+        ).header));
+        assert!(module.is_target(&Message::broadcast(Command::GetState, &vec![]).header));
+        assert!(module.is_target(&Message::multicast(7, Command::GetState, &vec![]).header));
+        assert!(!module.is_target(&Message::multicast(8, Command::GetState, &vec![]).header));
+    }
+
+    #[test]
+    #[should_panic]
+    fn bad_group() {
+        let mut module = Module::new("m", rand_type(), |_| {});
+        module.join_group(MAX_ID_VAL + 1);
     }
 
     #[test]
@@ -102,7 +245,7 @@ pub mod tests {
         let bad_size = rng.gen_range(MAX_ALIAS_SIZE + 1, MAX_ALIAS_SIZE + 100);
         let s = rng.gen_ascii_chars().take(bad_size).collect::<String>();
 
-        Module::new(&s, rand_type(), &|_| {});
+        Module::new(&s, rand_type(), |_| {});
     }
     pub fn rand_alias<'a>() -> String {
         let mut rng = thread_rng();
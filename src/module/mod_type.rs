@@ -1,3 +1,5 @@
+use core::mem;
+
 /// Available `Module` type
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ModuleType {
@@ -24,6 +26,14 @@ pub enum ModuleType {
     Handy,
 }
 impl ModuleType {
+    /// Converts a raw value into its `ModuleType` if it is a valid one.
+    pub fn from_u8(value: u8) -> Option<ModuleType> {
+        if value <= ModuleType::Handy as u8 {
+            Some(unsafe { mem::transmute::<u8, ModuleType>(value) })
+        } else {
+            None
+        }
+    }
     pub fn is_sensor(&self) -> bool {
         match *self {
             ModuleType::Potentiometer
@@ -0,0 +1,262 @@
+//! Registry of the modules created by a `Core`.
+//!
+//! Modules are kept in slots which are reused once their module is removed. Each slot counts its removals (its generation) so handles on a removed module can not reach the module taking its slot afterwards.
+
+use core::ops::{Index, IndexMut};
+use core::slice;
+use alloc::vec::Vec;
+
+use super::Module;
+
+/// Stable handle on a `Module` created by the `Core`.
+///
+/// A handle stays valid until its module is removed, even if other modules are created or removed in the meantime.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModuleHandle {
+    index: usize,
+    generation: u16,
+}
+
+impl ModuleHandle {
+    /// Index of the registry slot used by the module.
+    ///
+    /// *The slot is reused by the next created module once this one is removed.*
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+struct Slot<'a> {
+    generation: u16,
+    module: Option<Module<'a>>,
+}
+
+/// Modules of a `Core` addressed by `ModuleHandle`.
+pub struct Registry<'a> {
+    slots: Vec<Slot<'a>>,
+}
+
+impl<'a> Registry<'a> {
+    pub fn new() -> Registry<'a> {
+        Registry { slots: Vec::new() }
+    }
+    /// Adds a module in the first free slot and returns its handle.
+    pub fn add(&mut self, module: Module<'a>) -> ModuleHandle {
+        let index = match self.slots.iter().position(|slot| slot.module.is_none()) {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    module: None,
+                });
+                self.slots.len() - 1
+            }
+        };
+
+        let slot = &mut self.slots[index];
+        slot.module = Some(module);
+
+        ModuleHandle {
+            index,
+            generation: slot.generation,
+        }
+    }
+    /// Removes a module and frees its slot.
+    ///
+    /// Returns `None` if the handle is stale.
+    pub fn remove(&mut self, handle: ModuleHandle) -> Option<Module<'a>> {
+        if !self.contains(handle) {
+            return None;
+        }
+        let slot = &mut self.slots[handle.index];
+        slot.generation = slot.generation.wrapping_add(1);
+        slot.module.take()
+    }
+    /// Checks if the handle still designates a module.
+    pub fn contains(&self, handle: ModuleHandle) -> bool {
+        self.get(handle).is_some()
+    }
+    pub fn get(&self, handle: ModuleHandle) -> Option<&Module<'a>> {
+        match self.slots.get(handle.index) {
+            Some(slot) if slot.generation == handle.generation => slot.module.as_ref(),
+            _ => None,
+        }
+    }
+    pub fn get_mut(&mut self, handle: ModuleHandle) -> Option<&mut Module<'a>> {
+        let slot = match self.slots.get_mut(handle.index) {
+            Some(slot) => slot,
+            None => return None,
+        };
+        if slot.generation != handle.generation {
+            return None;
+        }
+        slot.module.as_mut()
+    }
+    /// Number of modules (free slots excluded).
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Returns the handles of all the modules in their slot order.
+    pub fn handles(&self) -> Vec<ModuleHandle> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|&(_, slot)| slot.module.is_some())
+            .map(|(index, slot)| ModuleHandle {
+                index,
+                generation: slot.generation,
+            })
+            .collect()
+    }
+    /// Iterates over the modules in their slot order.
+    pub fn iter<'r>(&'r self) -> Iter<'r, 'a> {
+        Iter {
+            slots: self.slots.iter(),
+        }
+    }
+    /// Iterates over the modules in their slot order.
+    pub fn iter_mut<'r>(&'r mut self) -> IterMut<'r, 'a> {
+        IterMut {
+            slots: self.slots.iter_mut(),
+        }
+    }
+}
+
+/// Access a module by its handle - *panics if the handle is stale.*
+impl<'a> Index<ModuleHandle> for Registry<'a> {
+    type Output = Module<'a>;
+
+    fn index(&self, handle: ModuleHandle) -> &Module<'a> {
+        match self.get(handle) {
+            Some(module) => module,
+            None => panic!("Stale module handle!"),
+        }
+    }
+}
+
+impl<'a> IndexMut<ModuleHandle> for Registry<'a> {
+    fn index_mut(&mut self, handle: ModuleHandle) -> &mut Module<'a> {
+        match self.get_mut(handle) {
+            Some(module) => module,
+            None => panic!("Stale module handle!"),
+        }
+    }
+}
+
+pub struct Iter<'r, 'a: 'r> {
+    slots: slice::Iter<'r, Slot<'a>>,
+}
+
+impl<'r, 'a: 'r> Iterator for Iter<'r, 'a> {
+    type Item = &'r Module<'a>;
+
+    fn next(&mut self) -> Option<&'r Module<'a>> {
+        while let Some(slot) = self.slots.next() {
+            if let Some(module) = slot.module.as_ref() {
+                return Some(module);
+            }
+        }
+        None
+    }
+}
+
+pub struct IterMut<'r, 'a: 'r> {
+    slots: slice::IterMut<'r, Slot<'a>>,
+}
+
+impl<'r, 'a: 'r> Iterator for IterMut<'r, 'a> {
+    type Item = &'r mut Module<'a>;
+
+    fn next(&mut self) -> Option<&'r mut Module<'a>> {
+        while let Some(slot) = self.slots.next() {
+            if let Some(module) = slot.module.as_mut() {
+                return Some(module);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use Message;
+    use module::tests::{rand_alias, rand_type};
+
+    #[test]
+    fn add_and_get() {
+        let cb = |_: Message| {};
+        let mut reg = Registry::new();
+        assert!(reg.is_empty());
+
+        let m1 = reg.add(Module::new("m1", rand_type(), &cb));
+        let m2 = reg.add(Module::new("m2", rand_type(), &cb));
+
+        assert_eq!(reg.len(), 2);
+        assert_eq!(reg.get(m1).unwrap().alias, "m1");
+        assert_eq!(reg.get(m2).unwrap().alias, "m2");
+
+        reg[m2].id = 42;
+        assert_eq!(reg[m2].id, 42);
+        assert_eq!(reg.handles(), vec![m1, m2]);
+    }
+    #[test]
+    fn stale_handle() {
+        let cb = |_: Message| {};
+        let mut reg = Registry::new();
+
+        let m1 = reg.add(Module::new(&rand_alias(), rand_type(), &cb));
+        let m2 = reg.add(Module::new("m2", rand_type(), &cb));
+
+        assert!(reg.remove(m1).is_some());
+        assert!(!reg.contains(m1));
+        assert!(reg.get(m1).is_none());
+        assert!(reg.get_mut(m1).is_none());
+        assert!(reg.remove(m1).is_none());
+        assert_eq!(reg.len(), 1);
+
+        // The new module takes the free slot but the old handle stays stale.
+        let m3 = reg.add(Module::new("m3", rand_type(), &cb));
+        assert_eq!(m3.index(), m1.index());
+        assert_ne!(m3, m1);
+        assert!(reg.get(m1).is_none());
+        assert_eq!(reg.get(m3).unwrap().alias, "m3");
+        assert_eq!(reg.get(m2).unwrap().alias, "m2");
+    }
+    #[test]
+    #[should_panic]
+    fn index_stale_handle() {
+        let cb = |_: Message| {};
+        let mut reg = Registry::new();
+
+        let m1 = reg.add(Module::new("m1", rand_type(), &cb));
+        reg.remove(m1);
+        reg.add(Module::new("m2", rand_type(), &cb));
+
+        reg[m1].id = 2;
+    }
+    #[test]
+    fn skip_free_slots() {
+        let cb = |_: Message| {};
+        let mut reg = Registry::new();
+
+        let m1 = reg.add(Module::new("m1", rand_type(), &cb));
+        let m2 = reg.add(Module::new("m2", rand_type(), &cb));
+        let m3 = reg.add(Module::new("m3", rand_type(), &cb));
+        reg.remove(m2);
+
+        let aliases: Vec<&str> = reg.iter().map(|module| module.alias.as_str()).collect();
+        assert_eq!(aliases, vec!["m1", "m3"]);
+
+        for module in reg.iter_mut() {
+            module.id = 7;
+        }
+        assert_eq!(reg.get(m1).unwrap().id, 7);
+        assert_eq!(reg.get(m3).unwrap().id, 7);
+        assert_eq!(reg.handles(), vec![m1, m3]);
+    }
+}
@@ -1,16 +1,17 @@
 use alloc::String;
 
-use {error, Command};
-use super::TargetMode;
+use error;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ParsingError {
-    InvalidCommand(Command),
+    InvalidCommand(u8),
+    /// Computed and received CRC.
     InvalidCrc((u16, u16)),
     InvalidDataSize(usize),
     InvalidHeaderSize(usize),
     InvalidProtocol(u8),
-    InvalidTargetMode(TargetMode),
+    /// Raw value of the unknown target mode.
+    InvalidTargetMode(u8),
 }
 
 impl error::Error for ParsingError {
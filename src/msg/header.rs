@@ -1,6 +1,5 @@
-use core::mem;
-
 use Command;
+use command;
 use super::error::ParsingError;
 use super::{MAX_DATA_SIZE, PROTOCOL_VERSION};
 
@@ -40,17 +39,21 @@ impl Header {
 
         let target = ((bytes[0] & 0b1111_0000) >> 4) as u16 | (bytes[1] as u16) << 4;
 
-        let target_mode = unsafe { mem::transmute::<u8, TargetMode>(bytes[2] & 0b0000_1111) };
-        if target_mode as u8 > TargetMode::Multicast as u8 {
-            return Err(ParsingError::InvalidTargetMode(target_mode));
-        }
+        let target_mode = match bytes[2] & 0b0000_1111 {
+            0 => TargetMode::Id,
+            1 => TargetMode::IdAck,
+            2 => TargetMode::Type,
+            3 => TargetMode::Broadcast,
+            4 => TargetMode::Multicast,
+            mode => return Err(ParsingError::InvalidTargetMode(mode)),
+        };
 
         let source = ((bytes[2] & 0b1111_0000) >> 4) as u16 | (bytes[3] as u16) << 4;
 
-        let command = unsafe { mem::transmute::<u8, Command>(bytes[4]) };
-        if command as u8 > Command::_GateProtocolOffsetNumber as u8 {
-            return Err(ParsingError::InvalidCommand(command));
-        }
+        let command = match command::from_u8(bytes[4]) {
+            Some(command) => command,
+            None => return Err(ParsingError::InvalidCommand(bytes[4])),
+        };
 
         let data_size = bytes[5] as usize;
         if data_size > MAX_DATA_SIZE {
@@ -77,7 +80,7 @@ impl Header {
         if self.target_mode as u8 > TargetMode::Multicast as u8 {
             panic!("target mode overflow.");
         }
-        if self.command as u8 > Command::_GateProtocolOffsetNumber as u8 {
+        if !command::is_valid(self.command as u8) {
             panic!("Command out of range!");
         }
 
@@ -98,6 +101,8 @@ impl Header {
 pub mod tests {
     use super::*;
 
+    use core::mem;
+
     extern crate rand;
     use self::rand::distributions::{IndependentSample, Range};
 
@@ -151,6 +156,39 @@ pub mod tests {
         let header = random_header();
         assert_eq!(header, Header::from_bytes(&header.to_bytes()).unwrap());
     }
+    #[test]
+    fn protocol_command() {
+        let mut header = random_header();
+        header.command = Command::GetId;
+        assert_eq!(header, Header::from_bytes(&header.to_bytes()).unwrap());
+    }
+    #[test]
+    fn invalid_command() {
+        let mut unmap = random_header().to_bytes();
+
+        unmap[4] = Command::_ProtocolEnd as u8;
+        assert_eq!(
+            Header::from_bytes(&unmap),
+            Err(ParsingError::InvalidCommand(Command::_ProtocolEnd as u8))
+        );
+        unmap[4] = Command::_GateProtocolOffsetNumber as u8;
+        assert_eq!(
+            Header::from_bytes(&unmap),
+            Err(ParsingError::InvalidCommand(
+                Command::_GateProtocolOffsetNumber as u8
+            ))
+        );
+    }
+    #[test]
+    fn invalid_target_mode() {
+        let mut unmap = random_header().to_bytes();
+
+        unmap[2] = (unmap[2] & 0b1111_0000) | 0b0000_0101;
+        assert_eq!(
+            Header::from_bytes(&unmap),
+            Err(ParsingError::InvalidTargetMode(5))
+        );
+    }
 
     fn random_header() -> Header {
         Header {
@@ -4,16 +4,16 @@ mod error;
 pub use self::error::ParsingError;
 
 mod header;
-pub use self::header::{Header, TargetMode, HEADER_SIZE};
+pub use self::header::{Header, TargetMode, HEADER_SIZE, MAX_ID_VAL};
 
 use Command;
 
 /// Current protocol revision.
-const PROTOCOL_VERSION: u8 = 0;
+pub const PROTOCOL_VERSION: u8 = 0;
 /// Specific target value used on Broadcast `TargetMode`.
 const BROADCAST_TARGET: u16 = 0x0FFF;
 /// Max size of the data vector.
-const MAX_DATA_SIZE: usize = 256;
+pub const MAX_DATA_SIZE: usize = 256;
 // CRC size
 pub const CRC_SIZE: usize = 2;
 
@@ -45,6 +45,17 @@ impl Message {
     pub fn id(target: u16, command: Command, data: &Vec<u8>) -> Message {
         Message::new(target, TargetMode::Id, command, data)
     }
+    /// Returns a pre-filled `TargetMode::Id` message used to answer from a module callback.
+    ///
+    /// The target is filled by the `Core` with the source of the received message.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - A `Command` struct designating the purpose of the message.
+    /// * `data` - A `&Vec<u8>` containing the data to transmit.
+    pub fn reply(command: Command, data: &Vec<u8>) -> Message {
+        Message::new(0, TargetMode::Id, command, data)
+    }
     /// Returns a pre-filled `TargetMode::IdAck` message used to send
     /// data to only one module and get an Acknowledgment (ACK) back.
     ///
@@ -2,28 +2,161 @@
 //!
 //! This module handles the physical aspect of the communication with the bus. In particular, it correctly sets the UART communication and the associated GPIOs.
 
+use collections::Priority;
+
+use core::cmp;
+
+/// Reception event raised by the interruptions and handled by `Core::poll`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RxEvent {
+    /// A byte has been received.
+    Byte(u8),
+    /// The bus stayed idle for a byte duration: the message being received is over (or corrupted).
+    Timeout,
+}
+
+/// Number of bytes the transmit queue of a `Port` can hold (see `Port::enqueue`).
+pub const TX_QUEUE_SIZE: usize = 512;
+/// Number of attempts to send a queued frame before dropping it because of collisions.
+pub const QUEUED_TX_ATTEMPTS: u8 = 8;
+
+/// Clock of the UART (in Hz).
+const FREQUENCY: u32 = 48000000;
+
+/// Checks if the UART can run at the given baudrate.
+///
+/// The clock divider of the baudrate needs to fit the baudrate register (`BRR`) of the UART: roughly from 1.5kBd to 6MBd.
+pub fn supports_baudrate(baudrate: u32) -> bool {
+    if baudrate < 2 {
+        return false;
+    }
+    let div = FREQUENCY / (baudrate / 2);
+    div >= 16 && div <= 0xFFFF
+}
+
+/// Random number of slots to wait before the `attempt`-th re-emission of a collided frame.
+///
+/// The range doubles with each attempt (up to 64 slots). `seed` is the state of the xorshift32 generator: it needs to differ from one board to another so two colliding boards do not draw the same delays (see `unique_seed`).
+pub fn backoff(seed: &mut u32, attempt: u16) -> u32 {
+    let mut x = *seed;
+    if x == 0 {
+        x = 0x9E37_79B9;
+    }
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    *seed = x;
+
+    1 + x % (1 << cmp::min(attempt, 6))
+}
+
+/// Access to the bus used by a `Core` to send and receive its frames.
+///
+/// `robus::init` binds the `Core` to the UART of the board (`UartPort`), other ports allow to run several cores on the host (see `SimBus`).
+pub trait Port {
+    /// Wait for the bus to be free and lock it for our transmission.
+    fn lock(&mut self);
+    /// Write a whole frame on the bus.
+    ///
+    /// Each byte is read back from the bus: the frame is aborted and `false` is returned as soon as the echo differs (another transmitter is talking at the same time).
+    fn send(&mut self, bytes: &[u8]) -> bool;
+    /// Queue a whole frame to be sent in the background once the bus is free.
+    ///
+    /// The frames are sent back to back by order of `Priority`: a frame only waits for the frames of the same or a higher priority, and for the one being sent. `Priority::Bulk` frames only get half of the transmit queue.
+    ///
+    /// Returns `false` (and queues nothing) if the frame does not fit in the remaining room of the transmit queue.
+    ///
+    /// A queued frame colliding with another transmission is sent again after a random delay (see `backoff`), up to `QUEUED_TX_ATTEMPTS` times: only this frame is dropped then.
+    fn enqueue(&mut self, bytes: &[u8], priority: Priority) -> bool;
+    /// Checks if all the queued frames have been handed to the bus.
+    fn tx_empty(&mut self) -> bool;
+    /// Returns the oldest reception event not handled yet.
+    fn poll(&mut self) -> Option<RxEvent>;
+    /// Returns the current baudrate of the bus (0 if unknown).
+    fn baudrate(&self) -> u32;
+    /// Change the baudrate of the bus.
+    fn set_baudrate(&mut self, baudrate: u32);
+}
+
 #[cfg(target_arch = "arm")]
 mod hard {
     use core;
 
-    use robus_core;
-    use recv_buf;
-    use Message;
+    use super::{backoff, supports_baudrate, Port, RxEvent, FREQUENCY, QUEUED_TX_ATTEMPTS,
+                TX_QUEUE_SIZE};
+    use collections::Priority;
+    use module::MAX_ALIAS_SIZE;
+    use storage::AliasStorage;
+    use topology::PtpLine;
+    use alloc::String;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
     use hal::rcc;
-    use ll::{TIM7 as TIMER7, USART1 as UART1, GPIOA, GPIOB, NVIC, RCC};
+    use ll::{TIM2 as TIMER2, TIM7 as TIMER7, USART1 as UART1, FLASH, GPIOA, GPIOB, NVIC, RCC};
     use ll::interrupt::*;
     use cortex_m;
 
-    const FREQUENCY: u32 = 48000000;
-
     static mut ROBUS_BAUDRATE: Option<u32> = None;
 
+    /// Set while the bus is busy (from the first received or sent byte until the timeout).
+    static mut TX_LOCK: bool = false;
+
+    const RX_FIFO_SIZE: usize = 512;
+
+    /// Reception events waiting for `poll` (the oldest events are kept when full).
+    static mut RX_FIFO: [RxEvent; RX_FIFO_SIZE] = [RxEvent::Timeout; RX_FIFO_SIZE];
+    static mut RX_HEAD: usize = 0;
+    static mut RX_TAIL: usize = 0;
+
+    /// One slot is kept empty to tell a full ring from an empty one.
+    const TX_RING_SIZE: usize = TX_QUEUE_SIZE + 1;
+
+    /// Frames queued by `enqueue`, sent by the UART interruption.
+    ///
+    /// The bytes are kept until their echo is checked (from `TX_ECHO` to `TX_TAIL`).
+    static mut TX_RING: [u8; TX_RING_SIZE] = [0; TX_RING_SIZE];
+    static mut TX_HEAD: usize = 0;
+    static mut TX_TAIL: usize = 0;
+    static mut TX_ECHO: usize = 0;
+    /// Set while the UART interruption is sending the queued frames.
+    static mut TX_RUNNING: bool = false;
+    /// Start of the frame whose echo is being checked, sent again after a collision.
+    static mut TX_FRAME: usize = 0;
+    static mut TX_ATTEMPTS: u8 = 0;
+
+    /// Max number of frames in the transmit queue (one slot is kept empty).
+    const TX_FRAMES: usize = 64;
+    /// End of each queued frame in `TX_RING`, from the frame at `TX_FRAME`.
+    static mut TX_ENDS: [usize; TX_FRAMES] = [0; TX_FRAMES];
+    /// Priority of each queued frame, along `TX_ENDS`.
+    static mut TX_PRIORITIES: [Priority; TX_FRAMES] = [Priority::Normal; TX_FRAMES];
+    static mut TX_ENDS_HEAD: usize = 0;
+    static mut TX_ENDS_TAIL: usize = 0;
+    /// Bytes of the queued `Priority::Bulk` frames.
+    static mut TX_BULK: usize = 0;
+    /// Idle bus timeouts left to wait before sending a collided frame again.
+    static mut TX_BACKOFF: u32 = 0;
+    /// State of the random backoff generator (see `unique_seed`).
+    static mut TX_SEED: u32 = 0;
+
+    /// Set while `send` checks the echo of its bytes.
+    static mut ECHO_CHECK: bool = false;
+    /// Last byte read back during `send`.
+    static mut ECHO: Option<u8> = None;
+    /// Number of loops waiting for an echo (a few byte durations).
+    const ECHO_TIMEOUT: u32 = 50_000;
+
     /// Change the robus main baudrate
     ///
     /// # Arguments
     ///
     /// * `baudrate` - A u32 specifying the communication baudrate
+    ///
+    /// The baudrates the UART can not run at are ignored (see `supports_baudrate`).
     pub fn set_baudrate(baudrate: u32) {
+        if !supports_baudrate(baudrate) {
+            return;
+        }
         cortex_m::interrupt::free(|cs| {
             let timer = TIMER7.borrow(cs);
             let uart = UART1.borrow(cs);
@@ -31,14 +164,19 @@ mod hard {
             unsafe {
                 ROBUS_BAUDRATE = Some(baudrate);
             }
-            uart.brr.write(|w| {
-                w.div_fraction()
-                    .bits((FREQUENCY / (baudrate / 2)) as u8 & 0x0F)
-            });
+            // BRR can only be written while the UART is disabled.
+            let enabled = uart.cr1.read().ue().bit_is_set();
+            uart.cr1.modify(|_, w| w.ue().disabled());
+            let div = FREQUENCY / (baudrate / 2);
             uart.brr.write(|w| {
                 w.div_mantissa()
-                    .bits(((FREQUENCY / (baudrate / 2)) >> 4) as u16)
+                    .bits((div >> 4) as u16)
+                    .div_fraction()
+                    .bits(div as u8 & 0x0F)
             });
+            if enabled {
+                uart.cr1.modify(|_, w| w.ue().enabled());
+            }
             timer
                 .arr
                 .modify(|_, w| w.arr().bits(((10000000 / baudrate) * 2) as u16));
@@ -50,12 +188,13 @@ mod hard {
     /// # Arguments
     ///
     /// * `baudrate` - A u32 specifying the communication baudrate
-    /// * `f` - A `FnMut(u8)` reception callback - *WARNING: it will be called inside the interruption!*
-    pub fn setup<F>(baudrate: u32, mut f: F)
-    where
-        F: FnMut(u8),
-    {
+    ///
+    /// The received bytes are kept until they are read with `poll`.
+    pub fn setup(baudrate: u32) {
         rcc::init();
+        unsafe {
+            TX_SEED = unique_seed();
+        }
         cortex_m::interrupt::free(|cs| {
             let rcc = RCC.borrow(cs);
             let gpioa = GPIOA.borrow(cs);
@@ -132,14 +271,11 @@ mod hard {
             // UART1 enabled
             uart.cr1.modify(|_, w| w.ue().enabled());
         });
-        unsafe {
-            RECV_CB = Some(extend_lifetime(&mut f));
-        }
     }
 
     /// Enable the Uart Interruption
     ///
-    /// The callback passed to the `setup` function may now be called.
+    /// The received bytes may now be read with `poll`.
     pub fn enable_interrupt() {
         cortex_m::interrupt::free(|cs| {
             let nvic = NVIC.borrow(cs);
@@ -148,7 +284,202 @@ mod hard {
         });
     }
 
-    static mut RECV_CB: Option<&'static mut FnMut(u8)> = None;
+    /// Returns the oldest reception event not handled yet.
+    pub fn poll() -> Option<RxEvent> {
+        cortex_m::interrupt::free(|_| unsafe {
+            if RX_TAIL == RX_HEAD {
+                return None;
+            }
+            let event = RX_FIFO[RX_TAIL];
+            RX_TAIL = (RX_TAIL + 1) % RX_FIFO_SIZE;
+            Some(event)
+        })
+    }
+
+    /// Push a reception event from the interruptions.
+    unsafe fn push_event(event: RxEvent) {
+        let next = (RX_HEAD + 1) % RX_FIFO_SIZE;
+        if next != RX_TAIL {
+            RX_FIFO[RX_HEAD] = event;
+            RX_HEAD = next;
+        }
+    }
+
+    /// Queue a frame to be sent by the UART interruption once the bus is free.
+    ///
+    /// The frame goes before the queued frames of a lower priority which are not being sent yet.
+    ///
+    /// Returns `false` if there is not enough room left in the queue.
+    pub fn enqueue(bytes: &[u8], priority: Priority) -> bool {
+        cortex_m::interrupt::free(|cs| unsafe {
+            let len = bytes.len();
+            let used = (TX_HEAD + TX_RING_SIZE - TX_ECHO) % TX_RING_SIZE;
+            let next_end = (TX_ENDS_HEAD + 1) % TX_FRAMES;
+            if len > TX_QUEUE_SIZE - used || next_end == TX_ENDS_TAIL {
+                return false;
+            }
+            // Bulk frames can not starve the other priorities.
+            if priority == Priority::Bulk && len > TX_QUEUE_SIZE / 2 - TX_BULK {
+                return false;
+            }
+
+            let (pos, frame) = insertion_point(priority);
+            // The following frames move `len` bytes further.
+            let mut i = TX_HEAD;
+            while i != pos {
+                i = (i + TX_RING_SIZE - 1) % TX_RING_SIZE;
+                TX_RING[(i + len) % TX_RING_SIZE] = TX_RING[i];
+            }
+            for (j, byte) in bytes.iter().enumerate() {
+                TX_RING[(pos + j) % TX_RING_SIZE] = *byte;
+            }
+            TX_HEAD = (TX_HEAD + len) % TX_RING_SIZE;
+            let mut k = TX_ENDS_HEAD;
+            while k != frame {
+                let previous = (k + TX_FRAMES - 1) % TX_FRAMES;
+                TX_ENDS[k] = (TX_ENDS[previous] + len) % TX_RING_SIZE;
+                TX_PRIORITIES[k] = TX_PRIORITIES[previous];
+                k = previous;
+            }
+            TX_ENDS[frame] = (pos + len) % TX_RING_SIZE;
+            TX_PRIORITIES[frame] = priority;
+            TX_ENDS_HEAD = next_end;
+            if priority == Priority::Bulk {
+                TX_BULK += len;
+            }
+            // Otherwise the transmission starts at the bus timeout.
+            if !core::ptr::read_volatile(&TX_LOCK) {
+                start_tx(cs);
+            }
+            true
+        })
+    }
+
+    /// Where a frame of the given priority is queued: before the first frame of a lower priority which is not being sent yet.
+    ///
+    /// Returns its position in `TX_RING` and its index in `TX_ENDS`.
+    unsafe fn insertion_point(priority: Priority) -> (usize, usize) {
+        let sent = (TX_TAIL + TX_RING_SIZE - TX_FRAME) % TX_RING_SIZE;
+        let mut start = TX_FRAME;
+        let mut frame = TX_ENDS_TAIL;
+        while frame != TX_ENDS_HEAD {
+            let offset = (start + TX_RING_SIZE - TX_FRAME) % TX_RING_SIZE;
+            // A collided frame keeps its place (and its attempts) until it goes through.
+            let collided = offset == 0 && TX_ATTEMPTS > 0;
+            if offset >= sent && !collided && TX_PRIORITIES[frame] > priority {
+                return (start, frame);
+            }
+            start = TX_ENDS[frame];
+            frame = (frame + 1) % TX_FRAMES;
+        }
+        (TX_HEAD, TX_ENDS_HEAD)
+    }
+
+    /// Forget the frame at `TX_FRAME` (sent or dropped), the next one is checked from now on.
+    unsafe fn pop_frame() {
+        let end = TX_ENDS[TX_ENDS_TAIL];
+        if TX_PRIORITIES[TX_ENDS_TAIL] == Priority::Bulk {
+            TX_BULK -= (end + TX_RING_SIZE - TX_FRAME) % TX_RING_SIZE;
+        }
+        TX_ENDS_TAIL = (TX_ENDS_TAIL + 1) % TX_FRAMES;
+        TX_FRAME = end;
+        TX_ATTEMPTS = 0;
+    }
+
+    /// Lock the bus and start sending the queued frames.
+    unsafe fn start_tx(cs: &cortex_m::interrupt::CriticalSection) {
+        // A collided frame waits for its backoff, even if the bus is free.
+        if TX_RUNNING || TX_HEAD == TX_TAIL || TX_BACKOFF > 0 {
+            return;
+        }
+        TX_RUNNING = true;
+        TX_LOCK = true;
+
+        let gpiob = GPIOB.borrow(cs);
+        let uart = UART1.borrow(cs);
+        // TX Enabled, RX kept for the echo -> \RE = 0 & DE = 1
+        gpiob.bsrr.write(|w| w.bs15().set_bit().br14().set_bit());
+        uart.cr1.modify(|_, w| w.txeie().enabled());
+    }
+
+    /// Check the echo of a queued byte (UART reception while sending).
+    unsafe fn check_echo(cs: &cortex_m::interrupt::CriticalSection, byte: u8) {
+        if TX_ECHO != TX_TAIL && TX_RING[TX_ECHO] == byte {
+            TX_ECHO = (TX_ECHO + 1) % TX_RING_SIZE;
+            if TX_ECHO == TX_ENDS[TX_ENDS_TAIL] {
+                // The whole frame went through.
+                pop_frame();
+            }
+            return;
+        }
+        // Collision: stop talking and send the frame again after a random delay.
+        let gpiob = GPIOB.borrow(cs);
+        let uart = UART1.borrow(cs);
+        uart.cr1
+            .modify(|_, w| w.txeie().disabled().tcie().disabled());
+        // RX Enabled -> \RE = 0 & DE = 0
+        gpiob.bsrr.write(|w| w.br15().set_bit().br14().set_bit());
+        TX_RUNNING = false;
+        TX_ATTEMPTS += 1;
+        if TX_ATTEMPTS < QUEUED_TX_ATTEMPTS {
+            // The previous frames already went through.
+            TX_TAIL = TX_FRAME;
+            TX_BACKOFF = backoff(&mut TX_SEED, TX_ATTEMPTS as u16);
+        } else {
+            // Only the collided frame is dropped.
+            pop_frame();
+            TX_TAIL = TX_FRAME;
+        }
+        TX_ECHO = TX_TAIL;
+        reset_timeout(cs);
+        resume_timeout(cs);
+    }
+
+    /// Send the next queued byte (UART transmit register empty).
+    unsafe fn transmit_next(cs: &cortex_m::interrupt::CriticalSection) {
+        let uart = UART1.borrow(cs);
+        if TX_HEAD == TX_TAIL {
+            // Wait for the last byte to be out before releasing the bus.
+            uart.cr1.modify(|_, w| w.txeie().disabled().tcie().enabled());
+            return;
+        }
+        uart.tdr.modify(|_, w| w.tdr().bits(TX_RING[TX_TAIL] as u16));
+        TX_TAIL = (TX_TAIL + 1) % TX_RING_SIZE;
+    }
+
+    /// Release the bus once the queued frames are sent (UART transmission complete).
+    unsafe fn end_tx(cs: &cortex_m::interrupt::CriticalSection) {
+        let gpiob = GPIOB.borrow(cs);
+        let uart = UART1.borrow(cs);
+        uart.cr1.modify(|_, w| w.tcie().disabled());
+        uart.icr.modify(|_, w| w.tccf().clear_bit());
+        // RX Enabled -> \RE = 0 & DE = 0
+        gpiob.bsrr.write(|w| w.br15().set_bit().br14().set_bit());
+        TX_RUNNING = false;
+        TX_ATTEMPTS = 0;
+        // TX_LOCK is released by the timeout, as for `send`.
+        reset_timeout(cs);
+        resume_timeout(cs);
+    }
+
+    /// Wait for the bus to be free and lock it for our transmission.
+    ///
+    /// The queued frames sent by the UART interruption need to be over as well.
+    pub fn lock_tx() {
+        loop {
+            // The timeout interruption may start the queued frames between the check and the lock.
+            let locked = cortex_m::interrupt::free(|_| unsafe {
+                if TX_LOCK || TX_RUNNING {
+                    return false;
+                }
+                TX_LOCK = true;
+                true
+            });
+            if locked {
+                return;
+            }
+        }
+    }
 
     /// Send a byte to the UART when it's ready.
     ///
@@ -167,16 +498,45 @@ mod hard {
             }
             let gpiob = GPIOB.borrow(cs);
             let uart1 = UART1.borrow(cs);
-            // TX Enabled -> \RE = 1 & DE = 1
-            gpiob.bsrr.write(|w| w.bs15().set_bit().bs14().set_bit());
+            // TX Enabled, RX kept for the echo -> \RE = 0 & DE = 1
+            gpiob.bsrr.write(|w| w.bs15().set_bit().br14().set_bit());
             while !transmit_complete(cs) {}
             uart1.tdr.modify(|_, w| w.tdr().bits(byte as u16));
         })
     }
 
-    pub fn send(msg: &mut Message) {
-        for byte in msg.to_bytes() {
-            send_when_ready(byte);
+    /// Wait for the echo of a sent byte.
+    ///
+    /// Returns `false` if another transmitter changed it (collision) or if it never came back.
+    fn wait_echo(byte: u8) -> bool {
+        for _ in 0..ECHO_TIMEOUT {
+            if let Some(echo) = unsafe { core::ptr::read_volatile(&ECHO) } {
+                return echo == byte;
+            }
+        }
+        false
+    }
+
+    /// Send a frame, aborted as soon as its echo differs.
+    ///
+    /// Returns `false` if the frame has been aborted.
+    pub fn send(bytes: &[u8]) -> bool {
+        let mut sent = true;
+        unsafe {
+            core::ptr::write_volatile(&mut ECHO_CHECK, true);
+        }
+        for byte in bytes {
+            unsafe {
+                core::ptr::write_volatile(&mut ECHO, None);
+            }
+            send_when_ready(*byte);
+            if !wait_echo(*byte) {
+                sent = false;
+                break;
+            }
+        }
+        unsafe {
+            core::ptr::write_volatile(&mut ECHO_CHECK, false);
         }
         // TX_LOCK unlock -> preambule idle bus during 1 byte duration
         cortex_m::interrupt::free(|cs| {
@@ -193,6 +553,7 @@ mod hard {
             reset_timeout(cs);
             resume_timeout(cs);
         });
+        sent
     }
 
     fn transmit_complete(cs: &cortex_m::interrupt::CriticalSection) -> bool {
@@ -208,6 +569,17 @@ mod hard {
     pub fn receive() {
         cortex_m::interrupt::free(|cs| {
             let uart = UART1.borrow(cs);
+            // Transmission of the queued frames
+            unsafe {
+                let cr1 = uart.cr1.read();
+                let isr = uart.isr.read();
+                if cr1.txeie().bit_is_set() && isr.txe().bit_is_set() {
+                    transmit_next(cs);
+                }
+                if cr1.tcie().bit_is_set() && isr.tc().bit_is_set() {
+                    end_tx(cs);
+                }
+            }
             if uart.isr.read().rxne().bit_is_set() {
                 // we receive something, start timeout
                 reset_timeout(cs);
@@ -216,16 +588,45 @@ mod hard {
                 let uart = UART1.borrow(cs);
                 let uart_val = uart.rdr.read().rdr().bits();
                 unsafe {
-                    if let Some(ref mut cb) = RECV_CB {
-                        cb(uart_val as u8);
+                    TX_LOCK = true;
+                    // While sending, we read back our own bytes.
+                    if ECHO_CHECK {
+                        ECHO = Some(uart_val as u8);
+                    } else if TX_RUNNING || TX_ECHO != TX_TAIL {
+                        check_echo(cs, uart_val as u8);
+                    } else {
+                        push_event(RxEvent::Byte(uart_val as u8));
                     }
                 }
             }
         });
     }
 
-    unsafe fn extend_lifetime<'a>(f: &'a mut FnMut(u8)) -> &'static mut FnMut(u8) {
-        core::mem::transmute::<&'a mut FnMut(u8), &'static mut FnMut(u8)>(f)
+    /// `Port` using the UART of the board, configured by `setup`.
+    pub struct UartPort {}
+
+    impl Port for UartPort {
+        fn lock(&mut self) {
+            lock_tx();
+        }
+        fn send(&mut self, bytes: &[u8]) -> bool {
+            send(bytes)
+        }
+        fn enqueue(&mut self, bytes: &[u8], priority: Priority) -> bool {
+            enqueue(bytes, priority)
+        }
+        fn tx_empty(&mut self) -> bool {
+            cortex_m::interrupt::free(|_| unsafe { TX_HEAD == TX_ECHO })
+        }
+        fn poll(&mut self) -> Option<RxEvent> {
+            poll()
+        }
+        fn baudrate(&self) -> u32 {
+            unsafe { ROBUS_BAUDRATE }.unwrap_or(0)
+        }
+        fn set_baudrate(&mut self, baudrate: u32) {
+            set_baudrate(baudrate);
+        }
     }
 
     /// Setup the timeout Timer
@@ -284,18 +685,245 @@ mod hard {
         timer.cr1.modify(|_, w| w.cen().enabled());
     }
 
+    /// Setup the clock Timer
+    ///
+    /// The 32 bits timer counts the microseconds since its setup (see `micros`).
+    pub fn setup_clock() {
+        cortex_m::interrupt::free(|cs| {
+            let rcc = RCC.borrow(cs);
+            let timer = TIMER2.borrow(cs);
+
+            //Enable TIM2 clock
+            rcc.apb1enr.modify(|_, w| w.tim2en().enabled());
+            // Set Prescaler Register -> 1MHz
+            timer.psc.modify(|_, w| w.psc().bits((FREQUENCY / 1_000_000 - 1) as u16));
+            // The prescaler is buffered: load it now (update event), not at the first overflow
+            timer.egr.write(|w| w.ug().set_bit());
+            timer.sr.modify(|_, w| w.uif().clear_bit());
+            // Count up to the max value of the Auto-Reload register (reset value)
+            timer.cr1.modify(|_, w| w.opm().continuous());
+            // Reset counter
+            timer.cnt.reset();
+            // Enable counter
+            timer.cr1.modify(|_, w| w.cen().enabled());
+        });
+    }
+
+    /// Returns the microseconds elapsed since `setup_clock` (wraps around after about 71 minutes).
+    pub fn micros() -> u32 {
+        cortex_m::interrupt::free(|cs| TIMER2.borrow(cs).cnt.read().bits())
+    }
+
+    /// Address of the 96 bits unique ID of the MCU.
+    const UID_BASE: u32 = 0x1FFF_F7AC;
+
+    /// Seed of the random delays, unique to the board (drawn from the unique ID of the MCU).
+    pub fn unique_seed() -> u32 {
+        let uid = |i: u32| unsafe { core::ptr::read_volatile((UID_BASE + 4 * i) as *const u32) };
+        uid(0) ^ uid(1).rotate_left(11) ^ uid(2).rotate_left(22)
+    }
+
+    /// Wait for the given duration.
+    ///
+    /// # Arguments
+    ///
+    /// * `ms` - A u32 specifying the duration in milliseconds
+    pub fn ms_delay(ms: u32) {
+        rcc::ms_delay(ms);
+    }
+
+    /// Point-to-point lines of the board: PTPA (PA8) and PTPB (PB13).
+    pub enum PtpPin {
+        A,
+        B,
+    }
+
+    impl PtpLine for PtpPin {
+        fn pull_low(&mut self) {
+            cortex_m::interrupt::free(|cs| match *self {
+                PtpPin::A => {
+                    let gpioa = GPIOA.borrow(cs);
+                    gpioa.bsrr.write(|w| w.br8().set_bit());
+                    gpioa.moder.modify(|_, w| w.moder8().output());
+                }
+                PtpPin::B => {
+                    let gpiob = GPIOB.borrow(cs);
+                    gpiob.bsrr.write(|w| w.br13().set_bit());
+                    gpiob.moder.modify(|_, w| w.moder13().output());
+                }
+            });
+        }
+        fn release(&mut self) {
+            // Back to input with pull-up
+            cortex_m::interrupt::free(|cs| match *self {
+                PtpPin::A => {
+                    let gpioa = GPIOA.borrow(cs);
+                    gpioa.moder.modify(|_, w| w.moder8().input());
+                }
+                PtpPin::B => {
+                    let gpiob = GPIOB.borrow(cs);
+                    gpiob.moder.modify(|_, w| w.moder13().input());
+                }
+            });
+        }
+        fn is_low(&self) -> bool {
+            cortex_m::interrupt::free(|cs| match *self {
+                PtpPin::A => {
+                    let gpioa = GPIOA.borrow(cs);
+                    gpioa.idr.read().idr8().bit_is_clear()
+                }
+                PtpPin::B => {
+                    let gpiob = GPIOB.borrow(cs);
+                    gpiob.idr.read().idr13().bit_is_clear()
+                }
+            })
+        }
+    }
+
+    /// Returns the point-to-point lines configured by `setup`.
+    pub fn ptp_lines() -> Vec<Box<PtpLine>> {
+        vec![
+            Box::new(PtpPin::A) as Box<PtpLine>,
+            Box::new(PtpPin::B) as Box<PtpLine>,
+        ]
+    }
+
+    /// Address of the flash page keeping the aliases (last page of the 128KB flash).
+    const ALIAS_PAGE: u32 = 0x0801_F800;
+    /// Number of aliases kept in the page.
+    const ALIAS_SLOTS: usize = 16;
+    /// Size of an alias slot: alias length + alias (even to be written by half-words).
+    const SLOT_SIZE: usize = MAX_ALIAS_SIZE + 1;
+
+    /// `AliasStorage` kept in the last page of the flash.
+    ///
+    /// The page (2KB from `0x0801_F800`) is reserved for the aliases: the linker script of the firmware must keep it out of the `FLASH` region (e.g. `FLASH : ORIGIN = 0x08000000, LENGTH = 126K` in `memory.x`), otherwise storing an alias erases the end of the firmware.
+    ///
+    /// The CPU stalls on the instructions fetched from the flash while the page is erased: the frames received meanwhile may still be lost.
+    pub struct FlashStorage {}
+
+    impl FlashStorage {
+        pub fn new() -> FlashStorage {
+            FlashStorage {}
+        }
+        fn read_slot(index: usize) -> [u8; SLOT_SIZE] {
+            let mut slot = [0xFF; SLOT_SIZE];
+            for (i, byte) in slot.iter_mut().enumerate() {
+                let addr = ALIAS_PAGE + (index * SLOT_SIZE + i) as u32;
+                *byte = unsafe { core::ptr::read_volatile(addr as *const u8) };
+            }
+            slot
+        }
+        /// Rewrites the page with the slot `index` replaced.
+        fn write_slot(index: usize, data: [u8; SLOT_SIZE]) {
+            // The whole page needs to be erased before being written again.
+            let mut page = [[0xFF; SLOT_SIZE]; ALIAS_SLOTS];
+            for (i, slot) in page.iter_mut().enumerate() {
+                *slot = FlashStorage::read_slot(i);
+            }
+            page[index] = data;
+
+            // Interrupts are only disabled to access the registers, not for the whole erase.
+            cortex_m::interrupt::free(|cs| {
+                let flash = FLASH.borrow(cs);
+                // Unlock the flash
+                if flash.cr.read().lock().bit_is_set() {
+                    flash.keyr.write(|w| unsafe { w.bits(0x4567_0123) });
+                    flash.keyr.write(|w| unsafe { w.bits(0xCDEF_89AB) });
+                }
+                // Erase the page
+                flash.cr.modify(|_, w| w.per().set_bit());
+                flash.ar.write(|w| unsafe { w.bits(ALIAS_PAGE) });
+                flash.cr.modify(|_, w| w.strt().set_bit());
+            });
+            wait_flash();
+            cortex_m::interrupt::free(|cs| {
+                let flash = FLASH.borrow(cs);
+                flash.cr.modify(|_, w| w.per().clear_bit());
+                flash.cr.modify(|_, w| w.pg().set_bit());
+            });
+            // Program the page by half-words
+            for (i, slot) in page.iter().enumerate() {
+                for j in 0..SLOT_SIZE / 2 {
+                    let half = slot[2 * j] as u16 | (slot[2 * j + 1] as u16) << 8;
+                    let addr = ALIAS_PAGE + (i * SLOT_SIZE + 2 * j) as u32;
+                    unsafe { core::ptr::write_volatile(addr as *mut u16, half) };
+                    wait_flash();
+                }
+            }
+            cortex_m::interrupt::free(|cs| {
+                let flash = FLASH.borrow(cs);
+                flash.cr.modify(|_, w| w.pg().clear_bit());
+                // Lock the flash
+                flash.cr.modify(|_, w| w.lock().set_bit());
+            });
+        }
+    }
+
+    impl AliasStorage for FlashStorage {
+        fn load(&self, index: usize) -> Option<String> {
+            if index >= ALIAS_SLOTS {
+                return None;
+            }
+            let slot = FlashStorage::read_slot(index);
+            let len = slot[0] as usize;
+            // Erased flash reads 0xFF.
+            if len == 0 || len > MAX_ALIAS_SIZE {
+                return None;
+            }
+            match core::str::from_utf8(&slot[1..len + 1]) {
+                Ok(alias) => Some(String::from(alias)),
+                Err(_) => None,
+            }
+        }
+        fn store(&mut self, index: usize, alias: &str) {
+            if index >= ALIAS_SLOTS || alias.len() > MAX_ALIAS_SIZE {
+                return;
+            }
+            let mut slot = [0xFF; SLOT_SIZE];
+            slot[0] = alias.len() as u8;
+            slot[1..alias.len() + 1].copy_from_slice(alias.as_bytes());
+            FlashStorage::write_slot(index, slot);
+        }
+        fn remove(&mut self, index: usize) {
+            // Spare the flash an erase if the slot is already empty.
+            if index < ALIAS_SLOTS && FlashStorage::read_slot(index)[0] != 0xFF {
+                FlashStorage::write_slot(index, [0xFF; SLOT_SIZE]);
+            }
+        }
+    }
+
+    /// Wait for the end of the current flash operation.
+    fn wait_flash() {
+        while cortex_m::interrupt::free(|cs| FLASH.borrow(cs).sr.read().bsy().bit_is_set()) {}
+    }
+
     pub fn timeout() {
         cortex_m::interrupt::free(|cs| {
             let timer = TIMER7.borrow(cs);
             // TX_LOCK release
-            unsafe {
-                robus_core::TX_LOCK = false;
-            }
+            let backing_off = unsafe {
+                TX_LOCK = false;
+                if TX_BACKOFF > 0 {
+                    // One more idle slot waited by the collided frame.
+                    TX_BACKOFF -= 1;
+                    true
+                } else {
+                    // The bus is free for the queued frames.
+                    start_tx(cs);
+                    false
+                }
+            };
             // Clear interrupt flag
             timer.sr.modify(|_, w| w.uif().clear_bit());
-            pause_timeout(cs);
+            // The timer keeps counting the idle slots of the backoff.
+            if !backing_off {
+                pause_timeout(cs);
+            }
             // flush message buffer
-            recv_buf::flush();
+            unsafe {
+                push_event(RxEvent::Timeout);
+            }
         });
     }
 
@@ -303,6 +931,15 @@ mod hard {
 
 #[cfg(not(target_arch = "arm"))]
 mod soft {
+    use std::thread;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::{Port, RxEvent};
+    use collections::Priority;
+    use topology::PtpLine;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
     /// Change the robus main baudrate
     ///
     /// # Arguments
@@ -314,16 +951,35 @@ mod soft {
     /// # Arguments
     ///
     /// * `baudrate` - A u32 specifying the communication baudrate
-    /// * `f` - A `FnMut(u8)` reception callback - *WARNING: it will be called inside the interruption!*
-    pub fn setup<F>(_baudrate: u32, mut _f: F)
-    where
-        F: FnMut(u8),
-    {
-    }
+    pub fn setup(_baudrate: u32) {}
     /// Enable the Uart Interruption
     ///
-    /// The callback passed to the `setup` function may now be called.
+    /// The received bytes may now be read with `poll`.
     pub fn enable_interrupt() {}
+    /// `Port` of the board UART: there is none on the host, so nothing is ever sent nor received.
+    ///
+    /// Use a `SimBus` to connect several cores instead.
+    pub struct UartPort {}
+
+    impl Port for UartPort {
+        fn lock(&mut self) {}
+        fn send(&mut self, _bytes: &[u8]) -> bool {
+            true
+        }
+        fn enqueue(&mut self, _bytes: &[u8], _priority: Priority) -> bool {
+            true
+        }
+        fn tx_empty(&mut self) -> bool {
+            true
+        }
+        fn poll(&mut self) -> Option<RxEvent> {
+            None
+        }
+        fn baudrate(&self) -> u32 {
+            0
+        }
+        fn set_baudrate(&mut self, _baudrate: u32) {}
+    }
     /// Send a byte to the UART when it's ready.
     ///
     /// *Beware, this function will block until the UART is ready to send.*
@@ -358,6 +1014,39 @@ mod soft {
     ///
     /// The timer is used to trigger timeout event and flush the reception buffer if we read corrupted data.
     pub fn setup_timeout() {}
+
+    /// Setup the clock Timer
+    ///
+    /// The host clock is used instead.
+    pub fn setup_clock() {}
+
+    /// Returns the microseconds elapsed since an arbitrary origin (wraps around after about 71 minutes).
+    pub fn micros() -> u32 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        (now.as_secs() * 1_000_000 + (now.subsec_nanos() / 1000) as u64) as u32
+    }
+
+    /// Seed of the random delays, drawn from the host clock so each call gets its own.
+    pub fn unique_seed() -> u32 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        now.subsec_nanos() ^ (now.as_secs() as u32).rotate_left(16)
+    }
+
+    /// Wait for the given duration.
+    ///
+    /// # Arguments
+    ///
+    /// * `ms` - A u32 specifying the duration in milliseconds
+    pub fn ms_delay(ms: u32) {
+        thread::sleep(Duration::from_millis(ms as u64));
+    }
+
+    /// Returns the point-to-point lines configured by `setup`.
+    ///
+    /// There is no PTP line on the host, use the simulated ones instead.
+    pub fn ptp_lines() -> Vec<Box<PtpLine>> {
+        Vec::new()
+    }
 }
 
 #[cfg(target_arch = "arm")]
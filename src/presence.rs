@@ -0,0 +1,315 @@
+//! Liveness of the remote modules
+//!
+//! Each `Core` may broadcast a `Command::Heartbeat` listing its modules at a fixed period (see `Core::set_heartbeat`). The listeners keep a presence table of the modules they heard of: a module turns suspect, then dead, when it stays silent for too long (see `Core::track_presence`).
+
+use {Command, Message};
+
+use module::ModuleHandle;
+
+use alloc::vec::Vec;
+
+/// Liveness of a remote module.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Presence {
+    /// Heard of recently.
+    Alive,
+    /// Silent for a while: a heartbeat may have been lost.
+    Suspect,
+    /// Silent for too long, or removed from the bus.
+    Dead,
+}
+
+/// Data of a `Command::Heartbeat`: the ids of the modules of the board.
+pub fn heartbeat(ids: &[u16]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(ids.len() * 2);
+    for id in ids {
+        data.push(*id as u8);
+        data.push((*id >> 8) as u8);
+    }
+    data
+}
+
+/// Reads the ids of the modules of a `Command::Heartbeat`.
+pub fn parse_heartbeat(data: &[u8]) -> Vec<u16> {
+    data.chunks(2)
+        .filter(|id| id.len() == 2)
+        .map(|id| id[0] as u16 | (id[1] as u16) << 8)
+        .collect()
+}
+
+/// Periodic heartbeat of our board.
+pub struct Heartbeat {
+    /// Period (in µs).
+    period: u32,
+    /// Time of the last heartbeat (in µs, see `physical::micros`).
+    last: u32,
+}
+
+impl Heartbeat {
+    /// The first heartbeat is sent right away.
+    pub fn new(period: u32, now: u32) -> Heartbeat {
+        Heartbeat {
+            period: period.saturating_mul(1000),
+            last: now.wrapping_sub(period.saturating_mul(1000)),
+        }
+    }
+    /// Checks if a heartbeat needs to be sent at `now` (in µs).
+    pub fn due(&mut self, now: u32) -> bool {
+        if now.wrapping_sub(self.last) < self.period {
+            return false;
+        }
+        self.last = now;
+        true
+    }
+}
+
+struct Node {
+    id: u16,
+    /// Last time we heard of it (in µs).
+    last_seen: u32,
+    presence: Presence,
+}
+
+/// Liveness of the modules heard of, with their changes since the last call (`transitions`).
+pub struct PresenceTable {
+    /// Silence before a module turns suspect (in µs).
+    suspect_after: u32,
+    /// Silence before a module is considered dead (in µs).
+    dead_after: u32,
+    nodes: Vec<Node>,
+}
+
+impl PresenceTable {
+    /// Creates an empty table with the given silences (in ms).
+    pub fn new(suspect_after: u32, dead_after: u32) -> PresenceTable {
+        PresenceTable {
+            suspect_after: suspect_after.saturating_mul(1000),
+            dead_after: dead_after.saturating_mul(1000),
+            nodes: Vec::new(),
+        }
+    }
+    /// A heartbeat of the module `id` has been received at `now` (in µs).
+    pub fn seen(&mut self, id: u16, now: u32, transitions: &mut Vec<(u16, Presence)>) {
+        if !self.refresh(id, now, transitions) {
+            self.nodes.push(Node {
+                id,
+                last_seen: now,
+                presence: Presence::Alive,
+            });
+            transitions.push((id, Presence::Alive));
+        }
+    }
+    /// A frame from the module `id` has been received at `now` (in µs).
+    ///
+    /// Returns `false` if the module is unknown, it is not added then.
+    pub fn refresh(&mut self, id: u16, now: u32, transitions: &mut Vec<(u16, Presence)>) -> bool {
+        match self.nodes.iter_mut().find(|node| node.id == id) {
+            Some(node) => {
+                node.last_seen = now;
+                if node.presence != Presence::Alive {
+                    node.presence = Presence::Alive;
+                    transitions.push((id, Presence::Alive));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+    /// The module `id` left the bus.
+    pub fn remove(&mut self, id: u16, transitions: &mut Vec<(u16, Presence)>) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.id == id) {
+            if node.presence != Presence::Dead {
+                node.presence = Presence::Dead;
+                transitions.push((id, Presence::Dead));
+            }
+        }
+    }
+    /// Update the liveness of the modules at `now` (in µs).
+    pub fn update(&mut self, now: u32, transitions: &mut Vec<(u16, Presence)>) {
+        for node in self.nodes.iter_mut() {
+            let silence = now.wrapping_sub(node.last_seen);
+            let presence = if silence >= self.dead_after {
+                Presence::Dead
+            } else if silence >= self.suspect_after {
+                Presence::Suspect
+            } else {
+                Presence::Alive
+            };
+            // Only a frame brings a module back to life.
+            if presence != node.presence && presence != Presence::Alive
+                && node.presence != Presence::Dead
+            {
+                node.presence = presence;
+                transitions.push((node.id, presence));
+            }
+        }
+    }
+    /// Returns the liveness of the module `id` (`None` if never heard of).
+    pub fn get(&self, id: u16) -> Option<Presence> {
+        self.nodes
+            .iter()
+            .find(|node| node.id == id)
+            .map(|node| node.presence)
+    }
+}
+
+/// Heartbeats of a `Core` and liveness of the remote modules it tracks.
+pub struct Liveness {
+    /// Module sending our heartbeats.
+    heartbeat: Option<(ModuleHandle, Heartbeat)>,
+    table: Option<PresenceTable>,
+}
+
+impl Liveness {
+    pub fn new() -> Liveness {
+        Liveness {
+            heartbeat: None,
+            table: None,
+        }
+    }
+    /// Sends a heartbeat from `sender` every `period` ms from `now` (in µs).
+    pub fn set_heartbeat(&mut self, sender: ModuleHandle, period: u32, now: u32) {
+        self.heartbeat = Some((sender, Heartbeat::new(period, now)));
+    }
+    pub fn stop_heartbeat(&mut self) {
+        self.heartbeat = None;
+    }
+    /// Returns the module sending our heartbeats if one is due at `now` (in µs).
+    pub fn heartbeat_due(&mut self, now: u32) -> Option<ModuleHandle> {
+        match self.heartbeat {
+            Some((sender, ref mut heartbeat)) if heartbeat.due(now) => Some(sender),
+            _ => None,
+        }
+    }
+    /// Starts tracking the remote modules with the given silences (in ms), forgetting the modules heard of so far.
+    pub fn track(&mut self, suspect_after: u32, dead_after: u32) {
+        self.table = Some(PresenceTable::new(suspect_after, dead_after));
+    }
+    /// Returns the liveness of the module `id` (`None` if never heard of or not tracked).
+    pub fn get(&self, id: u16) -> Option<Presence> {
+        match self.table {
+            Some(ref table) => table.get(id),
+            None => None,
+        }
+    }
+    /// A frame from the module `id` has been received at `now` (in µs): it is a sign of life.
+    pub fn heard(&mut self, id: u16, now: u32, transitions: &mut Vec<(u16, Presence)>) {
+        if let Some(ref mut table) = self.table {
+            table.refresh(id, now, transitions);
+        }
+    }
+    /// Handles a `Command::Heartbeat` (updating all the liveness) or a `Command::ModuleRemoved` received at `now` (in µs).
+    pub fn handle(&mut self, msg: &Message, now: u32, transitions: &mut Vec<(u16, Presence)>) {
+        let table = match self.table {
+            Some(ref mut table) => table,
+            None => return,
+        };
+        match msg.header.command {
+            Command::Heartbeat => {
+                for id in parse_heartbeat(&msg.data) {
+                    table.seen(id, now, transitions);
+                }
+                table.update(now, transitions);
+            }
+            Command::ModuleRemoved => table.remove(msg.header.source, transitions),
+            _ => {}
+        }
+    }
+    /// Update the liveness of the modules at `now` (in µs).
+    pub fn update(&mut self, now: u32, transitions: &mut Vec<(u16, Presence)>) {
+        if let Some(ref mut table) = self.table {
+            table.update(now, transitions);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use Module;
+    use module::Registry;
+    use module::tests::rand_type;
+
+    #[test]
+    fn heartbeat_payload() {
+        let ids = vec![2, 3, 0x0FFF];
+        assert_eq!(parse_heartbeat(&heartbeat(&ids)), ids);
+        assert!(parse_heartbeat(&[1]).is_empty());
+    }
+    #[test]
+    fn heartbeat_period() {
+        let mut heartbeat = Heartbeat::new(10, 5_000);
+        assert!(heartbeat.due(5_000));
+        assert!(!heartbeat.due(14_999));
+        assert!(heartbeat.due(15_000));
+    }
+    #[test]
+    fn transitions() {
+        let mut table = PresenceTable::new(10, 30);
+        let mut transitions = Vec::new();
+
+        table.seen(2, 0, &mut transitions);
+        table.seen(3, 0, &mut transitions);
+        assert!(!table.refresh(4, 0, &mut transitions));
+        assert_eq!(table.get(4), None);
+        assert_eq!(
+            transitions,
+            vec![(2, Presence::Alive), (3, Presence::Alive)]
+        );
+
+        transitions.clear();
+        table.seen(3, 9_000, &mut transitions);
+        table.update(10_000, &mut transitions);
+        assert_eq!(transitions, vec![(2, Presence::Suspect)]);
+
+        transitions.clear();
+        table.update(30_000, &mut transitions);
+        table.update(31_000, &mut transitions);
+        assert_eq!(transitions, vec![(2, Presence::Dead), (3, Presence::Suspect)]);
+        assert_eq!(table.get(2), Some(Presence::Dead));
+
+        // Back to life
+        transitions.clear();
+        table.refresh(2, 32_000, &mut transitions);
+        table.remove(3, &mut transitions);
+        table.update(33_000, &mut transitions);
+        assert_eq!(transitions, vec![(2, Presence::Alive), (3, Presence::Dead)]);
+    }
+    #[test]
+    fn liveness() {
+        let mut liveness = Liveness::new();
+        let mut transitions = Vec::new();
+        let mut heartbeat = Message::broadcast(Command::Heartbeat, &heartbeat(&[2, 3]));
+        heartbeat.header.source = 2;
+
+        // Not tracked
+        liveness.handle(&heartbeat, 0, &mut transitions);
+        assert!(transitions.is_empty());
+        assert_eq!(liveness.get(2), None);
+
+        liveness.track(10, 30);
+        liveness.handle(&heartbeat, 0, &mut transitions);
+        liveness.heard(2, 20_000, &mut transitions);
+        liveness.update(20_000, &mut transitions);
+        let mut removed = Message::broadcast(Command::ModuleRemoved, &vec![]);
+        removed.header.source = 2;
+        liveness.handle(&removed, 21_000, &mut transitions);
+        assert_eq!(
+            transitions,
+            vec![
+                (2, Presence::Alive),
+                (3, Presence::Alive),
+                (3, Presence::Suspect),
+                (2, Presence::Dead),
+            ]
+        );
+
+        let sender = Registry::new().add(Module::new("m1", rand_type(), |_| {}));
+        liveness.set_heartbeat(sender, 10, 0);
+        assert_eq!(liveness.heartbeat_due(0), Some(sender));
+        assert_eq!(liveness.heartbeat_due(9_999), None);
+        liveness.stop_heartbeat();
+        assert_eq!(liveness.heartbeat_due(20_000), None);
+    }
+}
@@ -1,68 +1,104 @@
-use msg::{Header, Message, CRC_SIZE, HEADER_SIZE};
+use msg::{Header, Message, ParsingError, CRC_SIZE, HEADER_SIZE};
 
 const BUF_SIZE: usize = 300;
 const MIN_MSG_SIZE: usize = HEADER_SIZE + CRC_SIZE;
 
-static mut BUF: [u8; BUF_SIZE] = [0; BUF_SIZE];
-static mut I: usize = 0;
-static mut TO_READ: usize = MIN_MSG_SIZE;
-static mut CRC: u16 = 0xFFFF;
+/// Reception buffer rebuilding the messages from the received bytes.
+pub struct RecvBuf {
+    buf: [u8; BUF_SIZE],
+    i: usize,
+    to_read: usize,
+    crc: u16,
+    /// Error of the last rejected frame not yet reported.
+    error: Option<ParsingError>,
+    /// Set while the end of a rejected frame is ignored (until the next flush).
+    discard: bool,
+}
 
-pub fn push(byte: u8) {
-    unsafe {
-        BUF[I] = byte;
-        I += 1;
+impl RecvBuf {
+    pub fn new() -> RecvBuf {
+        RecvBuf {
+            buf: [0; BUF_SIZE],
+            i: 0,
+            to_read: MIN_MSG_SIZE,
+            crc: 0xFFFF,
+            error: None,
+            discard: false,
+        }
+    }
+    pub fn push(&mut self, byte: u8) {
+        if self.discard {
+            return;
+        }
+        self.buf[self.i] = byte;
+        self.i += 1;
 
         // An entire header has been received
-        if I == HEADER_SIZE {
-            match Header::from_bytes(&BUF[..HEADER_SIZE]) {
+        if self.i == HEADER_SIZE {
+            match Header::from_bytes(&self.buf[..HEADER_SIZE]) {
                 Ok(h) => {
-                    TO_READ += h.data_size;
+                    self.to_read += h.data_size;
+                }
+                Err(e) => {
+                    self.flush();
+                    self.error = Some(e);
+                    // The rest of the frame is meaningless.
+                    self.discard = true;
+                    return;
                 }
-                Err(_e) => flush(),
             }
         }
 
         // Update the computed CRC
         // unless we are actually reading the sent one.
-        if I <= TO_READ - 2 {
-            update_crc(byte);
+        if self.i <= self.to_read - 2 {
+            self.update_crc(byte);
         }
     }
-}
-pub fn flush() {
-    unsafe {
-        I = 0;
-        TO_READ = MIN_MSG_SIZE;
-        CRC = 0xFFFF;
+    /// Number of bytes received for the current frame.
+    pub fn len(&self) -> usize {
+        self.i
     }
-}
-pub fn get_message() -> Option<Message> {
-    if unsafe { I == TO_READ } {
-        let msg = unsafe { Message::from_bytes(&BUF[..I], Some(CRC)) };
-        flush();
+    /// Checks if no frame is being received.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn flush(&mut self) {
+        self.discard = false;
+        self.i = 0;
+        self.to_read = MIN_MSG_SIZE;
+        self.crc = 0xFFFF;
+    }
+    /// Returns the received message once complete, or the error of the rejected frame.
+    pub fn get_message(&mut self) -> Option<Result<Message, ParsingError>> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        if self.i == self.to_read {
+            let msg = Message::from_bytes(&self.buf[..self.i], Some(self.crc));
+            self.flush();
 
-        if msg.is_ok() {
-            return Some(msg.unwrap());
+            return Some(msg);
         }
+        None
     }
-    None
-}
-unsafe fn update_crc(val: u8) {
-    let mut crc = CRC;
+    fn update_crc(&mut self, val: u8) {
+        let mut crc = self.crc;
 
-    let mut x = (crc >> 8) as u8 ^ val;
-    x ^= x >> 4;
-    // TODO: use the proper CRC computation
-    // This one is only kept for compatibility.
-    crc = ((crc << 8) as u32 ^ (x as u32) << 12 ^ (x as u32) << 5 ^ x as u32) as u16;
+        let mut x = (crc >> 8) as u8 ^ val;
+        x ^= x >> 4;
+        // TODO: use the proper CRC computation
+        // This one is only kept for compatibility.
+        crc = ((crc << 8) as u32 ^ (x as u32) << 12 ^ (x as u32) << 5 ^ x as u32) as u16;
 
-    CRC = crc;
+        self.crc = crc;
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use recv_buf;
+    use super::RecvBuf;
+    use msg::ParsingError;
     use msg::tests::rand_msg;
 
     extern crate rand;
@@ -70,7 +106,7 @@ mod tests {
 
     #[test]
     fn parse() {
-        recv_buf::flush();
+        let mut recv_buf = RecvBuf::new();
 
         let mut rng = rand::thread_rng();
         let n = Range::new(1, 10).ind_sample(&mut rng);
@@ -80,11 +116,53 @@ mod tests {
             let bytes = msg.to_bytes();
 
             for d in bytes[..bytes.len() - 1].iter() {
-                recv_buf::push(*d);
-                assert_eq!(recv_buf::get_message(), None);
+                recv_buf.push(*d);
+                assert_eq!(recv_buf.get_message(), None);
             }
-            recv_buf::push(bytes[bytes.len() - 1]);
-            assert_eq!(recv_buf::get_message(), Some(msg));
+            recv_buf.push(bytes[bytes.len() - 1]);
+            assert_eq!(recv_buf.get_message(), Some(Ok(msg)));
+        }
+    }
+    #[test]
+    fn reject_frames() {
+        let mut recv_buf = RecvBuf::new();
+
+        // Corrupted CRC
+        let mut bytes = rand_msg().to_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] = !bytes[last];
+        for d in bytes.iter() {
+            recv_buf.push(*d);
+        }
+        match recv_buf.get_message() {
+            Some(Err(ParsingError::InvalidCrc(_))) => {}
+            e => panic!("unexpected {:?}", e),
+        }
+        assert_eq!(recv_buf.get_message(), None);
+
+        // Unknown protocol
+        let mut bytes = rand_msg().to_bytes();
+        bytes[0] |= 0b0000_1111;
+        for d in bytes[..6].iter() {
+            recv_buf.push(*d);
+        }
+        assert_eq!(
+            recv_buf.get_message(),
+            Some(Err(ParsingError::InvalidProtocol(0b0000_1111)))
+        );
+        assert_eq!(recv_buf.get_message(), None);
+
+        // The end of the rejected frame is ignored until the bus timeout.
+        for d in bytes[6..].iter() {
+            recv_buf.push(*d);
+        }
+        assert!(recv_buf.is_empty());
+        recv_buf.flush();
+
+        let msg = rand_msg();
+        for d in msg.to_bytes() {
+            recv_buf.push(d);
         }
+        assert_eq!(recv_buf.get_message(), Some(Ok(msg)));
     }
 }
@@ -1,20 +1,125 @@
 //! Robus core - handles the intern mechanisms for creating modules and dispatch them the received messages.
 
-use {Message, Module, ModuleType};
+use {error, Command, Message, Module, ModuleType};
 
-use msg::TargetMode;
-use recv_buf;
+use baudrate::{self, Action, Switcher};
+use benchmark::{self, BenchmarkReport, Counter, MIN_FRAME_DATA};
+use clock::{self, BoardClock, TickSource, TimeKeeper};
+use collections::Priority;
+use directory::{Directory, Discovery};
+use module::{ModuleHandle, Registry, DEFAULT_ID};
+use storage::AliasStorage;
+use msg::{Header, ParsingError, TargetMode, PROTOCOL_VERSION};
+use physical::{self, Port, RxEvent, UartPort};
+use presence::{self, Liveness, Presence};
+use recv_buf::RecvBuf;
+use stats::Stats;
+use topology::{self, DetectionError, Network, Ptp, PtpLine, Topology};
+use transfer::{TransferError, Transfers, MAX_TRANSFER_SIZE};
 
-use core;
+use core::{cmp, mem, str};
+use alloc::String;
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 
-#[cfg(target_arch = "arm")]
-use physical;
+/// Id of the gate, root of the topology detection.
+const ROOT_ID: u16 = 1;
+/// Time given to the modules to answer the detection requests (in ms).
+const DETECTION_TIMEOUT: u32 = 100;
+/// `PokeNext` answer once all the branches have been explored.
+const NO_BRANCH: u8 = 0xFF;
 
-#[cfg(target_arch = "arm")]
-pub static mut TX_LOCK: bool = false;
+/// Error raised by the reception of a frame (see `Core::set_error_callback`).
+#[derive(Clone, Debug, PartialEq)]
+pub enum BusError {
+    /// The frame has been rejected (e.g. invalid CRC, protocol, data size or target mode).
+    Parsing(ParsingError),
+    /// The bus timed out in the middle of a frame, after the given number of bytes.
+    Timeout(usize),
+    /// A transfer larger than a frame could not be rebuilt (see `Core::send_large`).
+    Transfer(TransferError),
+}
+
+impl error::Error for BusError {
+    fn description(&self) -> String {
+        match *self {
+            BusError::Parsing(ref e) => error::Error::description(e),
+            BusError::Timeout(received) => {
+                format!("Bus timeout after {} bytes of a frame", received)
+            }
+            BusError::Transfer(ref e) => error::Error::description(e),
+        }
+    }
+}
+
+/// Error raised when a `Message` could not be delivered.
+#[derive(Debug, PartialEq)]
+pub enum DeliveryError {
+    /// The targeted module never acknowledged the message.
+    NoAck(u16),
+}
+
+impl error::Error for DeliveryError {
+    fn description(&self) -> String {
+        match *self {
+            DeliveryError::NoAck(id) => format!("No acknowledgment from module {}", id),
+        }
+    }
+}
+
+/// Error raised when a `Message` could not be sent on the bus.
+#[derive(Debug, PartialEq)]
+pub enum SendError {
+    /// The transmit queue is full: the `Message` should be sent again later.
+    WouldBlock,
+    /// The frame kept colliding with other transmissions (see `set_collision_retries`).
+    Collision,
+    /// The data of the given size does not fit in a transfer (see `MAX_TRANSFER_SIZE`).
+    TooLarge(usize),
+    /// The UART can not run at the given baudrate (see `Core::switch_baudrate`).
+    UnsupportedBaudrate(u32),
+}
+
+impl error::Error for SendError {
+    fn description(&self) -> String {
+        match *self {
+            SendError::WouldBlock => String::from("Transmit queue full"),
+            SendError::Collision => String::from("Frame collided on each attempt"),
+            SendError::TooLarge(size) => {
+                format!("{} bytes do not fit in a transfer of {} bytes", size, MAX_TRANSFER_SIZE)
+            }
+            SendError::UnsupportedBaudrate(baudrate) => {
+                format!("The UART can not run at {} baud", baudrate)
+            }
+        }
+    }
+}
 
-static mut REGISTRY: Option<Vec<Module>> = None;
+/// Error raised when a request did not get its answer.
+#[derive(Debug, PartialEq)]
+pub enum RequestError {
+    /// The command does not expect any answer.
+    NoReplyExpected(Command),
+    /// The targeted module did not answer in time.
+    Timeout(u16),
+    /// The request could not be sent.
+    Send(SendError),
+    /// The answer of the given module is malformed.
+    InvalidReply(u16),
+}
+
+impl error::Error for RequestError {
+    fn description(&self) -> String {
+        match *self {
+            RequestError::NoReplyExpected(command) => {
+                format!("Command {:?} does not expect any reply", command)
+            }
+            RequestError::Timeout(id) => format!("No reply from module {}", id),
+            RequestError::Send(ref e) => error::Error::description(e),
+            RequestError::InvalidReply(id) => format!("Invalid reply from module {}", id),
+        }
+    }
+}
 
 /// Handles the intern mechanisms for creating modules and dispatch them the received messages.
 ///
@@ -24,175 +129,1406 @@ static mut REGISTRY: Option<Vec<Module>> = None;
 /// * creating new Module
 /// * dispatching Message to the targeted Module
 ///
+/// Messages between modules of the same `Core` are delivered directly, without using the bus. The bus is only used when modules of other boards may be targeted. A module never receives its own messages unless it asks for (see `set_module_echo`). The replies of our modules to our own modules wait for the next `poll` (see `create_module_with_reply`).
+///
+/// The `Core` owns its modules: their callbacks may borrow any data living at least as long as the `Core` (`'a`).
+///
+/// The bus services (transfers, benchmark, baudrate switch, liveness, bus time, discovery) keep their own state, the `Core` routes their messages to them and does the sending on their behalf.
 ///
-/// Note: *Only one Core should be created as it handles the hardware configuration (e.g. UART interruption).*
-pub struct Core {}
+/// Note: *Only one Core should be bound to the hardware (see `robus::init`) as it handles the hardware configuration (e.g. UART interruption). Other cores can be created freely (e.g. for testing purpose).*
+pub struct Core<'a> {
+    registry: Registry<'a>,
+    port: Box<Port>,
+    /// Re-emissions allowed after a collision.
+    tx_retries: u8,
+    /// State of the random generator of the collision backoff.
+    seed: u32,
+    recv_buf: RecvBuf,
+    /// Replies of our modules to our own modules (with their sender), delivered by `poll`.
+    local_replies: Vec<(Message, ModuleHandle)>,
+    transfers: Transfers,
+    benchmark: Counter,
+    baudrates: Switcher,
+    liveness: Liveness,
+    time: TimeKeeper,
+    discovery: Discovery,
+    ticks: Box<TickSource>,
+    /// Local time at the reception of the `Message` being handled (in µs).
+    handled_at: u64,
+    stats: Stats,
+    ptp: Option<Ptp>,
+    firm_revision: &'static str,
+    alias_storage: Option<Box<AliasStorage>>,
+    lost_callback: Option<Box<FnMut(u16) + 'a>>,
+    error_callback: Option<Box<FnMut(BusError) + 'a>>,
+    large_callback: Option<Box<FnMut(Message) + 'a>>,
+    presence_callback: Option<Box<FnMut(u16, Presence) + 'a>>,
+    /// Source and command of the reply the `Core` is currently waiting for.
+    awaited: Option<(u16, Command)>,
+    reply: Option<Message>,
+    /// Local time at the reception of the reply (in µs).
+    reply_at: u64,
+}
 
-impl Core {
-    /// Creates a `Core` and setup the Module registry and the reception buffer.
-    ///
-    /// Note: *Only one Core should be created as it handles the hardware configuration (e.g. UART interruption).*
-    /// TODO: We should make the Core a singleton or panic! if called multiple times.
-    pub fn new() -> Core {
-        unsafe {
-            REGISTRY = Some(Vec::new());
+impl<'a> Core<'a> {
+    /// Creates a `Core` with an empty Module registry and reception buffer.
+    pub fn new() -> Core<'a> {
+        Core {
+            registry: Registry::new(),
+            port: Box::new(UartPort {}),
+            tx_retries: 3,
+            seed: physical::unique_seed(),
+            recv_buf: RecvBuf::new(),
+            local_replies: Vec::new(),
+            transfers: Transfers::new(),
+            benchmark: Counter::new(),
+            baudrates: Switcher::new(),
+            liveness: Liveness::new(),
+            time: TimeKeeper::new(),
+            discovery: Discovery::new(),
+            ticks: Box::new(BoardClock::new()),
+            handled_at: 0,
+            stats: Stats::default(),
+            ptp: None,
+            firm_revision: env!("CARGO_PKG_VERSION"),
+            alias_storage: None,
+            lost_callback: None,
+            error_callback: None,
+            large_callback: None,
+            presence_callback: None,
+            awaited: None,
+            reply: None,
+            reply_at: 0,
         }
-
-        Core {}
     }
     /// Create a new `Module` attached with the Robus `Core`.
     ///
     /// # Arguments
     /// * `alias`: a `&str` representing the name of the `Module`
     /// * `mod_type`: the `ModuleType` caracterising the `Module`
-    /// * `cb`: the reception callback `Fn(Message)` called each time a `Message` targetting this module is received.
-    pub fn create_module<'a>(
+    /// * `cb`: the reception callback `FnMut(Message)` called each time a `Message` targetting this module is received. It may update its own state (e.g. a `move` closure owning a pin).
+    ///
+    /// Returns the `ModuleHandle` used to designate the `Module` until it is removed.
+    pub fn create_module<F>(&mut self, alias: &str, mod_type: ModuleType, cb: F) -> ModuleHandle
+    where
+        F: FnMut(Message) + 'a,
+    {
+        self.add_module(Module::new(alias, mod_type, cb))
+    }
+    /// Create a new `Module` answering the messages it receives.
+    ///
+    /// The `Message` returned by the callback is sent back to the source of the received one once the bus is free, there is no need to fill its target (see `Message::reply`). Our own modules get it at the next `poll`: two modules answering each other never recurse.
+    ///
+    /// # Arguments
+    /// * `alias`: a `&str` representing the name of the `Module`
+    /// * `mod_type`: the `ModuleType` caracterising the `Module`
+    /// * `cb`: the reception callback `FnMut(Message) -> Option<Message>` called each time a `Message` targetting this module is received.
+    ///
+    /// Returns the `ModuleHandle` used to designate the `Module` until it is removed.
+    pub fn create_module_with_reply<F>(
         &mut self,
-        alias: &'a str,
+        alias: &str,
         mod_type: ModuleType,
-        cb: &'a Fn(Message),
-    ) -> usize {
-        let module = Module::new(alias, mod_type, cb);
+        cb: F,
+    ) -> ModuleHandle
+    where
+        F: FnMut(Message) -> Option<Message> + 'a,
+    {
+        self.add_module(Module::with_reply(alias, mod_type, cb))
+    }
+    /// Create a new `ModuleType::Sniffer` module observing the whole bus.
+    ///
+    /// Sniffers receive every valid frame, whatever its target (protocol messages included), and an event for each frame rejected by the reception. Replies returned by sniffers are dropped.
+    ///
+    /// # Arguments
+    /// * `alias`: a `&str` representing the name of the `Module`
+    /// * `on_frame`: the `FnMut(Message)` callback called for each valid frame
+    /// * `on_reject`: the `FnMut(ParsingError)` callback called for each rejected frame
+    pub fn create_sniffer<F, E>(&mut self, alias: &str, on_frame: F, on_reject: E) -> ModuleHandle
+    where
+        F: FnMut(Message) + 'a,
+        E: FnMut(ParsingError) + 'a,
+    {
+        let mut module = Module::new(alias, ModuleType::Sniffer, on_frame);
+        module.error_callback = Some(Box::new(on_reject));
+        self.add_module(module)
+    }
+    fn add_module(&mut self, module: Module<'a>) -> ModuleHandle {
+        let handle = self.registry.add(module);
+
+        if let Some(ref storage) = self.alias_storage {
+            if let Some(alias) = storage.load(handle.index()) {
+                self.registry[handle].set_alias(&alias);
+            }
+        }
+        handle
+    }
+    /// Remove a `Module` from the `Core`
+    ///
+    /// The `Module` stops receiving messages and its handle becomes stale: using it afterwards panics. If the `Module` was given a bus id, the other boards are told it left the bus (`Command::ModuleRemoved`).
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` of the `Module` to remove
+    ///
+    /// Returns `false` if the handle was already stale.
+    pub fn remove_module(&mut self, mod_id: ModuleHandle) -> bool {
+        let id = match self.registry.remove(mod_id) {
+            Some(module) => module.id,
+            None => return false,
+        };
+        // The slot of the module may be reused by the next one created.
+        if let Some(ref mut storage) = self.alias_storage {
+            storage.remove(mod_id.index());
+        }
 
-        let reg = unsafe { get_registry() };
-        unsafe {
-            reg.push(extend_lifetime(module));
+        if id != DEFAULT_ID {
+            let mut msg = Message::broadcast(Command::ModuleRemoved, &Vec::new());
+            msg.header.source = id;
+            // Only the other boards need to be told.
+            self.sniff(&msg);
+            // The module is gone anyway: the boards which missed it will find out by themselves.
+            let _ = self.write(&msg, true);
+        }
+        true
+    }
+    /// Checks if the handle still designates a `Module` (i.e. it has not been removed)
+    pub fn has_module(&self, mod_id: ModuleHandle) -> bool {
+        self.registry.contains(mod_id)
+    }
+    /// Let a module receive the messages it sends itself
+    ///
+    /// By default a module never receives its own messages (e.g. the broadcasts it sends).
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` used by the `Core` to identify a `Module`
+    /// * `echo`: whether the module receives its own messages
+    pub fn set_module_echo(&mut self, mod_id: ModuleHandle, echo: bool) {
+        self.registry[mod_id].echo = echo;
+    }
+    /// Set the callback called when a remote module disappears from the bus
+    ///
+    /// The callback receives the bus id of the module. A module is considered gone when it announces its removal (`Command::ModuleRemoved`) or when its heartbeats stop (see `track_presence`).
+    ///
+    /// # Arguments
+    /// * `cb`: the `FnMut(u16)` callback
+    pub fn set_module_lost_callback<F>(&mut self, cb: F)
+    where
+        F: FnMut(u16) + 'a,
+    {
+        self.lost_callback = Some(Box::new(cb));
+    }
+    /// Set the callback called for each frame lost by the reception
+    ///
+    /// The callback receives the typed error: a rejected frame (`BusError::Parsing`) or a frame interrupted by the bus timeout (`BusError::Timeout`). It may e.g. log the error or blink a fault LED.
+    ///
+    /// # Arguments
+    /// * `cb`: the `FnMut(BusError)` callback
+    pub fn set_error_callback<F>(&mut self, cb: F)
+    where
+        F: FnMut(BusError) + 'a,
+    {
+        self.error_callback = Some(Box::new(cb));
+    }
+    /// Set the callback called for each `Message` rebuilt from a transfer (see `send_large`)
+    ///
+    /// Only the transfers targeting our modules are rebuilt. The `Message` may carry up to `MAX_TRANSFER_SIZE` bytes of data: it can not be sent back as is. The lost transfers are reported to the error callback (`BusError::Transfer`).
+    ///
+    /// # Arguments
+    /// * `cb`: the `FnMut(Message)` callback
+    pub fn set_large_message_callback<F>(&mut self, cb: F)
+    where
+        F: FnMut(Message) + 'a,
+    {
+        self.large_callback = Some(Box::new(cb));
+    }
+    /// Returns the current alias of a module
+    ///
+    /// The alias may differ from the one given at creation if it has been rewritten through the bus (`Command::WriteAlias`).
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` used by the `Core` to identify a `Module`
+    pub fn module_alias(&self, mod_id: ModuleHandle) -> &str {
+        &self.registry[mod_id].alias
+    }
+    /// Set the storage used to keep the aliases rewritten through the bus
+    ///
+    /// The aliases already stored are applied to the existing and future modules.
+    ///
+    /// # Arguments
+    /// * `storage`: the `AliasStorage` (e.g. `MemoryStorage`)
+    pub fn set_alias_storage(&mut self, storage: Box<AliasStorage>) {
+        for handle in self.registry.handles() {
+            if let Some(alias) = storage.load(handle.index()) {
+                self.registry[handle].set_alias(&alias);
+            }
         }
-        reg.len() - 1
+        self.alias_storage = Some(storage);
     }
     /// Change the module id used on the bus
     ///
     /// # Arguments
-    /// * `mod_id`: the internal id `usize` used by the `Core` to identify a `Module`
+    /// * `mod_id`: the `ModuleHandle` used by the `Core` to identify a `Module`
     /// * `robus_id`: a `u16` id identifying the `Module` on the bus. It is typically determined by the topology detection.
     ///
     /// Note: *The bus id is global to the whole bus and may thus differ from the local id used for the module registry.*
     ///
     /// TODO: this function should probably be private only (kept for testing purpose).
-    pub fn set_module_id(&mut self, mod_id: usize, robus_id: u16) {
-        let reg = unsafe { get_registry() };
-        let module = &mut reg[mod_id];
-        module.id = robus_id;
+    pub fn set_module_id(&mut self, mod_id: ModuleHandle, robus_id: u16) {
+        self.registry[mod_id].id = robus_id;
+    }
+    /// Returns the module id used on the bus
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` used by the `Core` to identify a `Module`
+    pub fn module_id(&self, mod_id: ModuleHandle) -> u16 {
+        self.registry[mod_id].id
+    }
+    /// Set the `Port` used to access the bus
+    ///
+    /// The `Core` uses the UART of the board by default (`UartPort`).
+    ///
+    /// # Arguments
+    /// * `port`: the `Port` (e.g. a `SimPort` connected to a `SimBus` on the host)
+    pub fn set_port(&mut self, port: Box<Port>) {
+        self.port = port;
+    }
+    /// Returns a snapshot of the bus statistics
+    ///
+    /// Useful to diagnose a faulty bus (e.g. a bad cable raising CRC errors).
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+    /// Reset all the bus statistics to zero
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+    }
+    /// Set how many times a frame is sent again after colliding with another transmission (3 by default)
+    ///
+    /// Each re-emission waits for a random delay, whose range doubles with each attempt, so the colliding boards quickly stop talking at the same time. Once the retries are exhausted, `send` returns `SendError::Collision`.
+    ///
+    /// # Arguments
+    /// * `retries`: the number of re-emissions allowed after the first attempt
+    pub fn set_collision_retries(&mut self, retries: u8) {
+        self.tx_retries = retries;
+    }
+    /// Set the point-to-point lines used by the topology detection
+    ///
+    /// *`robus::init` already sets the lines of the board.*
+    ///
+    /// # Arguments
+    /// * `lines`: the `PtpLine` of each branch
+    pub fn set_ptp_lines(&mut self, lines: Vec<Box<PtpLine>>) {
+        self.ptp = Some(Ptp::new(lines));
+    }
+    /// Detect the modules connected to the bus and give them unique ids
+    ///
+    /// The `Core` acts as the gate: its own modules take the first ids, then each detected board gives consecutive ids to its modules following the topology.
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` of the `Module` driving the detection
+    pub fn detect_modules(&mut self, mod_id: ModuleHandle) -> Result<Topology, DetectionError> {
+        let mut reset = Message::broadcast(Command::ResetDetection, &Vec::new());
+        if self.send(mod_id, &mut reset).is_err() {
+            return Err(DetectionError::ResetFailed);
+        }
+
+        for (i, module) in self.registry.iter_mut().enumerate() {
+            module.id = ROOT_ID + i as u16;
+        }
+        let first_id = ROOT_ID + self.registry.len() as u16;
+
+        if let Some(ref mut ptp) = self.ptp {
+            ptp.set_root();
+        }
+
+        let mut net = BusNetwork { core: self, mod_id };
+        topology::detect_topology(&mut net, ROOT_ID, first_id)
+    }
+    /// Change the status word of a module
+    ///
+    /// The status is sent to the gate when it asks for it (`Command::GetStatus`).
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` used by the `Core` to identify a `Module`
+    /// * `status`: the `u16` status word
+    pub fn set_module_status(&mut self, mod_id: ModuleHandle, status: u16) {
+        self.registry[mod_id].status = status;
+    }
+    /// Change the firmware revision sent to the gate when it asks for it (`Command::GetFirmRevision`)
+    ///
+    /// The revision defaults to the robus version.
+    ///
+    /// # Arguments
+    /// * `revision`: a non empty `&str` designating the firmware revision, of up to 255 bytes
+    pub fn set_firm_revision(&mut self, revision: &'static str) {
+        // The answer's data size is sent in a single byte.
+        if revision.is_empty() || revision.len() > u8::max_value() as usize {
+            panic!("revision size({}) out of range.", revision.len());
+        }
+        self.firm_revision = revision;
+    }
+    /// Add a `Module` to a multicast group
+    ///
+    /// The `Module` will then receive all the `TargetMode::Multicast` messages sent to this group.
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` used by the `Core` to identify a `Module`
+    /// * `group`: a `u16` designating the multicast group (max value is actually a u12)
+    pub fn join_group(&mut self, mod_id: ModuleHandle, group: u16) {
+        self.registry[mod_id].join_group(group);
+    }
+    /// Remove a `Module` from a multicast group
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` used by the `Core` to identify a `Module`
+    /// * `group`: a `u16` designating the multicast group
+    pub fn leave_group(&mut self, mod_id: ModuleHandle, group: u16) {
+        self.registry[mod_id].leave_group(group);
+    }
+    /// Handle the bytes received since the last call
+    ///
+    /// The bytes are received in the UART interruption but only dispatched to the modules from here: it needs to be called regularly (e.g. in the main loop).
+    pub fn poll(&mut self) {
+        // Only the replies queued so far, the ones they raise wait for the next call.
+        let replies = mem::replace(&mut self.local_replies, Vec::new());
+        for (reply, from) in replies {
+            self.deliver(&reply, Some(from));
+        }
+        while let Some(event) = self.port.poll() {
+            match event {
+                RxEvent::Byte(byte) => self.receive(byte),
+                RxEvent::Timeout => {
+                    let received = self.recv_buf.len();
+                    self.recv_buf.flush();
+                    if received > 0 {
+                        self.stats.count_timeout();
+                        self.report(BusError::Timeout(received));
+                    }
+                }
+            }
+        }
+        self.update_baudrate();
+        self.update_presence();
+        self.expire_transfers();
+        self.send_heartbeat();
+        self.send_time();
     }
     /// Robus byte reception callback
     ///
     /// # Arguments
     /// * `byte`: the received `u8` byte
     ///
-    /// TODO: this function should probably be private only (called from the robus::init?).
+    /// TODO: this function should probably be private only (called from `poll`).
     pub fn receive(&mut self, byte: u8) {
-        #[cfg(target_arch = "arm")]
-        unsafe {
-            TX_LOCK = true;
-        }
+        self.stats.count_byte();
+        self.recv_buf.push(byte);
 
-        recv_buf::push(byte);
+        let msg = match self.recv_buf.get_message() {
+            Some(Ok(msg)) => msg,
+            Some(Err(error)) => {
+                self.stats.count_error(&error);
+                self.reject(error.clone());
+                self.report(BusError::Parsing(error));
+                return;
+            }
+            None => return,
+        };
 
-        if let Some(msg) = recv_buf::get_message() {
-            let reg = unsafe { get_registry() };
+        self.stats.count_rx(msg.header.target_mode);
+        // The bus works at the new baudrate.
+        self.baudrates.confirm();
+        // Any frame is a sign of life.
+        let mut transitions = Vec::new();
+        let now = self.micros();
+        self.liveness.heard(msg.header.source, now, &mut transitions);
+        self.notify_presence(transitions);
 
-            let matches = match msg.header.target_mode {
-                TargetMode::Broadcast => reg.iter().filter(|_| true).collect(),
-                TargetMode::Id => reg.iter()
-                    .filter(|module| {
-                        module.id == msg.header.target || module.mod_type == ModuleType::Sniffer
-                    })
-                    .collect(),
-                _ => Vec::new(),
-            };
+        // Sniffers see every frame, even the ones handled by the Core itself.
+        self.sniff(&msg);
+        self.handle(msg, None);
+    }
+    /// Handle a valid frame, received from the bus or sent by one of our modules (`from`).
+    fn handle(&mut self, msg: Message, from: Option<ModuleHandle>) {
+        self.handled_at = self.ticks.micros();
+        let msg = match self.catch_reply(msg) {
+            Some(msg) => msg,
+            None => return,
+        };
+        if msg.header.command == Command::Introduction && self.discovery.collect(&msg) {
+            return;
+        }
+
+        if msg.header.target_mode == TargetMode::IdAck {
+            self.acknowledge(&msg);
+        }
+        match msg.header.command {
+            Command::Fragment => {
+                if self.is_targeted(&msg.header, from) {
+                    self.reassemble(&msg);
+                }
+                return;
+            }
+            Command::SetBaudrate => {
+                // Our own switches are scheduled by `switch_baudrate`.
+                if msg.header.target_mode == TargetMode::Broadcast && from.is_none() {
+                    let previous = self.port.baudrate();
+                    let now = self.micros();
+                    self.baudrates.handle(&msg, previous, now);
+                }
+                return;
+            }
+            // The benchmark is run by the Core itself.
+            Command::DataRate | Command::DataResult => {
+                if msg.header.target_mode == TargetMode::Id && self.is_targeted(&msg.header, from) {
+                    // Both ends of the measure are reception times.
+                    let received = self.handled_at as u32;
+                    if let Some(mut answer) = self.benchmark.handle(&msg, received) {
+                        self.reply(msg.header.target, &mut answer);
+                    }
+                }
+                return;
+            }
+            _ => {}
+        }
+        // Protocol messages are handled by the Core and never reach the modules.
+        if msg.header.command.is_protocol() {
+            self.handle_protocol(&msg);
+            return;
+        }
 
-            for ref module in matches.iter() {
-                // TODO: could we use a ref instead?
+        self.dispatch(&msg, from);
+    }
+    /// Give a `Message` to its targeted modules (but its sender `from`) and send their replies.
+    fn dispatch(&mut self, msg: &Message, from: Option<ModuleHandle>) {
+        let mut replies = Vec::new();
+        for handle in self.registry.handles() {
+            let module = &mut self.registry[handle];
+            // Sniffers already got the message.
+            if module.mod_type == ModuleType::Sniffer || !module.is_target(&msg.header) {
+                continue;
+            }
+            if from == Some(handle) && !module.echo {
+                continue;
+            }
+            self.stats.count_dispatch();
+            // TODO: could we use a ref instead?
+            if let Some(mut reply) = (module.callback)(msg.clone()) {
+                reply.header.target = msg.header.source;
+                reply.header.target_mode = TargetMode::Id;
+                replies.push((handle, module.id, reply));
+            }
+        }
+        for (handle, id, mut reply) in replies {
+            reply.header.source = id;
+            if self.is_remote(&reply.header) {
+                // The requester handles the missing replies (e.g. `send_reliable`).
+                let _ = self.write(&reply, true);
+            }
+            self.local_replies.push((reply, handle));
+        }
+    }
+    /// Checks if a message targets one of our modules (but its sender `from`), as `dispatch` does.
+    fn is_targeted(&self, header: &Header, from: Option<ModuleHandle>) -> bool {
+        self.registry.handles().into_iter().any(|handle| {
+            let module = &self.registry[handle];
+            module.mod_type != ModuleType::Sniffer && module.is_target(header)
+                && (from != Some(handle) || module.echo)
+        })
+    }
+    /// Add a fragment to its transfer, the rebuilt `Message` is given to the large message callback.
+    fn reassemble(&mut self, fragment: &Message) {
+        let mut errors = Vec::new();
+        let rebuilt = self.transfers.handle(fragment, self.handled_at, &mut errors);
+        for error in errors {
+            self.report(BusError::Transfer(error));
+        }
+        if let Some(msg) = rebuilt {
+            if let Some(ref mut cb) = self.large_callback {
+                cb(msg);
+            }
+        }
+    }
+    /// Drop the transfers without any fragment for `TRANSFER_TIMEOUT`.
+    fn expire_transfers(&mut self) {
+        let mut errors = Vec::new();
+        let now = self.ticks.micros();
+        self.transfers.expire(now, &mut errors);
+        for error in errors {
+            self.report(BusError::Transfer(error));
+        }
+    }
+    /// Give a valid frame to the sniffers.
+    fn sniff(&mut self, msg: &Message) {
+        for module in self.registry.iter_mut() {
+            if module.mod_type == ModuleType::Sniffer {
+                // Sniffers only observe the bus: their replies are dropped.
                 (module.callback)(msg.clone());
             }
         }
     }
+    /// Tell the sniffers a frame has been rejected.
+    fn reject(&mut self, error: ParsingError) {
+        for module in self.registry.iter_mut() {
+            if let Some(ref mut cb) = module.error_callback {
+                cb(error.clone());
+            }
+        }
+    }
+    /// Give a reception error to the user.
+    fn report(&mut self, error: BusError) {
+        if let Some(ref mut cb) = self.error_callback {
+            cb(error);
+        }
+    }
     /// Send a `Message` on the bus
     ///
     /// # Arguments
-    /// * `mod_id`: the `usize` id of the sending `Module`
+    /// * `mod_id`: the `ModuleHandle` of the sending `Module`
     /// * `msg`: the `Message` to send (needs to be mut as we will inject the source inside)
     ///
-    pub fn send(&mut self, mod_id: usize, msg: &mut Message) {
-        let reg = unsafe { get_registry() };
-        let module = &reg[mod_id];
-        msg.header.source = module.id;
-        // Wait tx unlock
-        #[cfg(target_arch = "arm")]
-        unsafe { while core::ptr::read_volatile(&TX_LOCK) {} }
-        // Lock transmission
-        #[cfg(target_arch = "arm")]
-        unsafe {
-            TX_LOCK = true;
-        }
-        #[cfg(target_arch = "arm")]
-        physical::send(msg);
-
-        #[cfg(test)]
-        // Use a local loop for unit-testing
-        for byte in msg.to_bytes() {
-            self.receive(byte);
-        }
+    /// Returns `SendError::Collision` if the frame kept colliding on the bus (see `set_collision_retries`), our own modules get the message anyway.
+    pub fn send(&mut self, mod_id: ModuleHandle, msg: &mut Message) -> Result<(), SendError> {
+        let id = self.registry[mod_id].id;
+        self.transmit(id, msg, Some(mod_id))
     }
-}
+    /// Send a `Message` carrying more data than a frame allows
+    ///
+    /// The data is split into `Command::Fragment` frames, rebuilt by the targeted boards and given to their large message callback (see `set_large_message_callback`) rather than to their modules. Small messages may be sent this way as well.
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` of the sending `Module`
+    /// * `msg`: the `Message` to send, with up to `MAX_TRANSFER_SIZE` bytes of data (needs to be mut as we will inject the source inside)
+    ///
+    /// Returns `SendError::TooLarge` if the data does not fit, nothing is sent then. If a fragment can not be sent, the transfer is aborted with its error.
+    pub fn send_large(&mut self, mod_id: ModuleHandle, msg: &mut Message) -> Result<(), SendError> {
+        if msg.data.len() > MAX_TRANSFER_SIZE {
+            return Err(SendError::TooLarge(msg.data.len()));
+        }
+        msg.header.source = self.registry[mod_id].id;
 
-unsafe fn get_registry() -> &'static mut Vec<Module<'static>> {
-    if let Some(ref mut reg) = REGISTRY {
-        reg
-    } else {
-        panic!("Core Module Registry not initialized!")
+        for mut fragment in self.transfers.split(msg) {
+            self.send(mod_id, &mut fragment)?;
+        }
+        Ok(())
     }
-}
-
-unsafe fn extend_lifetime<'a>(f: Module<'a>) -> Module<'static> {
-    core::mem::transmute::<Module<'a>, Module<'static>>(f)
-}
-
-#[cfg(test)]
-mod tests {
-    extern crate std;
-
-    use super::*;
-
-    use self::std::time;
-    use self::std::rc::Rc;
-    use self::std::cell::RefCell;
+    /// Send a `Message` without waiting for the bus
+    ///
+    /// The frame is queued and sent in the background (by the UART interruption) once the bus is free, our own modules get the message right away. Unlike `send`, it never stalls the main loop.
+    ///
+    /// The `Message` is queued with the `Priority::Normal` (see `enqueue`).
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` of the sending `Module`
+    /// * `msg`: the `Message` to send (needs to be mut as we will inject the source inside)
+    ///
+    /// Returns `SendError::WouldBlock` if the transmit queue is full, nothing is sent then.
+    pub fn try_send(&mut self, mod_id: ModuleHandle, msg: &mut Message) -> Result<(), SendError> {
+        self.enqueue(mod_id, msg, Priority::Normal)
+    }
+    /// Send a `Message` without waiting for the bus, ahead of the queued messages of lower priority
+    ///
+    /// The port sends the queued frames by order of priority so an urgent message (e.g. `Command::StepperStop`) only waits for the frame being sent.
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` of the sending `Module`
+    /// * `msg`: the `Message` to send (needs to be mut as we will inject the source inside)
+    /// * `priority`: the `Priority` of the `Message`
+    ///
+    /// Returns `SendError::WouldBlock` if the transmit queue is full, nothing is sent then.
+    pub fn enqueue(
+        &mut self,
+        mod_id: ModuleHandle,
+        msg: &mut Message,
+        priority: Priority,
+    ) -> Result<(), SendError> {
+        msg.header.source = self.registry[mod_id].id;
+        if self.is_remote(&msg.header) {
+            let bytes = msg.to_bytes();
+            if !self.port.enqueue(&bytes, priority) {
+                return Err(SendError::WouldBlock);
+            }
+            self.count_tx(msg, bytes.len());
+        }
+        self.deliver(msg, Some(mod_id));
+        Ok(())
+    }
+    /// Send a `Message` from the bus id `source` (sent by our module `from`)
+    ///
+    /// The bus is used once free if remote modules may be targeted, our own modules get the message directly.
+    fn transmit(
+        &mut self,
+        source: u16,
+        msg: &mut Message,
+        from: Option<ModuleHandle>,
+    ) -> Result<(), SendError> {
+        msg.header.source = source;
+        let sent = if self.is_remote(&msg.header) {
+            self.write(msg, true)
+        } else {
+            Ok(())
+        };
+        self.deliver(msg, from);
+        sent
+    }
+    /// Send a `Message` on the bus and wait for its acknowledgment
+    ///
+    /// The `Message` is sent as a `TargetMode::IdAck` message and re-sent until the targeted `Module` acknowledges it. A missing acknowledgment may come from a noisy bus: the module is not considered gone (see `set_module_lost_callback`).
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` of the sending `Module`
+    /// * `msg`: the `Message` to send (needs to be mut as we will inject the source and target mode inside)
+    /// * `retries`: the number of re-emissions allowed after the first attempt
+    /// * `timeout`: the `u32` time to wait for the acknowledgment of each attempt (in ms)
+    ///
+    pub fn send_reliable(
+        &mut self,
+        mod_id: ModuleHandle,
+        msg: &mut Message,
+        retries: u8,
+        timeout: u32,
+    ) -> Result<(), DeliveryError> {
+        msg.header.target_mode = TargetMode::IdAck;
 
-    use module::tests::rand_type;
-    use msg::tests::{rand_command, rand_data, rand_data_size, rand_id};
+        for _ in 0..(retries as u16 + 1) {
+            // The ACK may arrive before the end of the send, so we need to wait for it beforehand.
+            self.await_reply(msg.header.target, Command::Ack);
+            if self.send(mod_id, msg).is_err() {
+                // Counts as a lost attempt.
+                self.awaited = None;
+                continue;
+            }
 
-    macro_rules! wait_timeout {
-        ($evt: expr, $dur: expr, $cb: expr) => (
-            let now = time::SystemTime::now();
-            while !$evt.is_set() {
-                let dt = now.elapsed().unwrap();
-                if dt > $dur {
-                    $cb();
-                    break;
+            if let Some(ack) = self.wait_reply(timeout) {
+                if ack.data == [msg.header.command as u8] {
+                    return Ok(());
                 }
             }
-        );
+        }
+        Err(DeliveryError::NoAck(msg.header.target))
     }
-    #[test]
-    fn fill_source_on_send() {
-        let mut core = Core::new();
-        let mut msg = rand_id_msg();
-
-        let from = rand_id();
-
-        let m1 = core.create_module("m1", rand_type(), &|_| {});
-        core.set_module_id(m1, from);
+    /// Send a request to a module and wait for its answer
+    ///
+    /// The answer is the first `Message` coming from `target` with the reply command expected for `command` (e.g. `Command::PublishState` for `Command::GetState`, see `Command::expected_reply`). It is given back here and never reaches the modules callbacks.
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` of the sending `Module`
+    /// * `target`: the `u16` bus id of the requested module
+    /// * `command`: the request `Command`
+    /// * `data`: the data of the request
+    /// * `timeout`: the `u32` time to wait for the answer (in ms)
+    pub fn request(
+        &mut self,
+        mod_id: ModuleHandle,
+        target: u16,
+        command: Command,
+        data: &Vec<u8>,
+        timeout: u32,
+    ) -> Result<Message, RequestError> {
+        let reply = match command.expected_reply() {
+            Some(reply) => reply,
+            None => return Err(RequestError::NoReplyExpected(command)),
+        };
 
-        core.send(m1, &mut msg);
+        // The answer may arrive before the end of the send, so we need to wait for it beforehand.
+        self.await_reply(target, reply);
+        let mut msg = Message::id(target, command, data);
+        if let Err(e) = self.send(mod_id, &mut msg) {
+            self.awaited = None;
+            return Err(RequestError::Send(e));
+        }
 
-        assert_eq!(msg.header.source, from);
+        match self.wait_reply(timeout) {
+            Some(answer) => Ok(answer),
+            None => Err(RequestError::Timeout(target)),
+        }
     }
+    /// Change the baudrate of all the boards of the bus at once
+    ///
+    /// The new baudrate is broadcast (`Command::SetBaudrate`) and applied by every `Core` in `poll` once `delay` is over, our own included. Our `Core` then confirms the switch at the new baudrate: the boards which do not hear from the bus in time go back to the previous baudrate.
+    ///
+    /// The delay needs to leave enough time for the broadcast to reach all the boards (a few ms).
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` of the `Module` sending the switch
+    /// * `baudrate`: the new `u32` baudrate
+    /// * `delay`: the `u16` time before the switch (in ms)
+    ///
+    /// Returns `SendError::UnsupportedBaudrate` if the UART can not run at the new baudrate, nothing is sent then.
+    pub fn switch_baudrate(
+        &mut self,
+        mod_id: ModuleHandle,
+        baudrate: u32,
+        delay: u16,
+    ) -> Result<(), SendError> {
+        if !physical::supports_baudrate(baudrate) {
+            return Err(SendError::UnsupportedBaudrate(baudrate));
+        }
+        let data = baudrate::announce(baudrate, delay);
+        let mut msg = Message::broadcast(Command::SetBaudrate, &data);
+        self.send(mod_id, &mut msg)?;
+
+        let previous = self.port.baudrate();
+        let id = self.registry[mod_id].id;
+        let now = self.micros();
+        self.baudrates.schedule(baudrate, previous, delay, now, Some(id));
+        Ok(())
+    }
+    /// Broadcast a heartbeat every `period` ms so the other boards know we are alive (see `track_presence`)
+    ///
+    /// The heartbeats (`Command::Heartbeat`) list the ids of all our modules. They are queued by `poll` with the `Priority::High`, the first one right away.
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` of the `Module` sending the heartbeats
+    /// * `period`: the `u32` time between two heartbeats (in ms)
+    pub fn set_heartbeat(&mut self, mod_id: ModuleHandle, period: u32) {
+        let now = self.micros();
+        self.liveness.set_heartbeat(mod_id, period, now);
+    }
+    /// Stop sending heartbeats
+    pub fn stop_heartbeat(&mut self) {
+        self.liveness.stop_heartbeat();
+    }
+    /// Track the liveness of the remote modules from their heartbeats
+    ///
+    /// A module is alive once heard of (heartbeat or any frame it sends), suspect when silent for `suspect_after` ms and dead when silent for `dead_after` ms or once removed. Each change is given to the callback, a dead module is also given to the module lost callback (see `set_module_lost_callback`).
+    ///
+    /// The silences need to be a few heartbeat periods long so a lost heartbeat does not kill a module.
+    ///
+    /// # Arguments
+    /// * `suspect_after`: the `u32` silence before a module turns suspect (in ms)
+    /// * `dead_after`: the `u32` silence before a module is considered dead (in ms)
+    /// * `cb`: the `FnMut(u16, Presence)` callback, receiving the bus id of the module and its new liveness
+    pub fn track_presence<F>(&mut self, suspect_after: u32, dead_after: u32, cb: F)
+    where
+        F: FnMut(u16, Presence) + 'a,
+    {
+        self.liveness.track(suspect_after, dead_after);
+        self.presence_callback = Some(Box::new(cb));
+    }
+    /// Returns the liveness of a remote module (`None` if never heard of or not tracked)
+    ///
+    /// # Arguments
+    /// * `id`: the `u16` bus id of the module
+    pub fn presence(&self, id: u16) -> Option<Presence> {
+        self.liveness.get(id)
+    }
+    /// Queue our heartbeat when due.
+    fn send_heartbeat(&mut self) {
+        let now = self.micros();
+        let sender = match self.liveness.heartbeat_due(now) {
+            Some(sender) => sender,
+            None => return,
+        };
+        let source = match self.registry.get(sender) {
+            Some(module) => module.id,
+            None => {
+                // The sender has been removed.
+                self.liveness.stop_heartbeat();
+                return;
+            }
+        };
+        let ids: Vec<u16> = self.registry
+            .iter()
+            .map(|module| module.id)
+            .filter(|id| *id != DEFAULT_ID)
+            .collect();
+
+        let mut msg = Message::broadcast(Command::Heartbeat, &presence::heartbeat(&ids));
+        msg.header.source = source;
+        self.sniff(&msg);
+        // Only the other boards need it, a dropped heartbeat is replaced by the next one.
+        let bytes = msg.to_bytes();
+        if self.port.enqueue(&bytes, Priority::High) {
+            self.count_tx(&msg, bytes.len());
+        }
+    }
+    /// Update the liveness of the remote modules.
+    fn update_presence(&mut self) {
+        let mut transitions = Vec::new();
+        let now = self.micros();
+        self.liveness.update(now, &mut transitions);
+        self.presence_changed(transitions);
+    }
+    /// Give the liveness changes to the user and forget the dead modules.
+    fn presence_changed(&mut self, transitions: Vec<(u16, Presence)>) {
+        let dead: Vec<u16> = transitions
+            .iter()
+            .filter(|&&(_, presence)| presence == Presence::Dead)
+            .map(|&(id, _)| id)
+            .collect();
+        self.notify_presence(transitions);
+        for id in dead {
+            self.module_lost(id);
+        }
+    }
+    /// Give the liveness changes to the user.
+    fn notify_presence(&mut self, transitions: Vec<(u16, Presence)>) {
+        for (id, presence) in transitions {
+            if let Some(ref mut cb) = self.presence_callback {
+                cb(id, presence);
+            }
+        }
+    }
+    /// Set the source of the local time of the `Core` (the board clock by default)
+    ///
+    /// All the timings of the `Core` (bus time, heartbeats, baudrate switch, benchmark) rely on it.
+    ///
+    /// # Arguments
+    /// * `ticks`: the `TickSource`
+    pub fn set_tick_source(&mut self, ticks: Box<TickSource>) {
+        self.ticks = ticks;
+    }
+    /// Make our `Core` the time master of the bus
+    ///
+    /// Our local time becomes the bus time: it is broadcast every `period` ms (`Command::TimeSync`) and given to the boards synchronizing with `sync_time`. The broadcasts of other masters are ignored.
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` of the `Module` sending the time
+    /// * `period`: the `u32` time between two broadcasts (in ms)
+    pub fn set_time_master(&mut self, mod_id: ModuleHandle, period: u32) {
+        let now = self.ticks.micros();
+        self.time.set_master(mod_id, period, now);
+    }
+    /// Synchronize our bus time with the time master
+    ///
+    /// The offset of our local time is estimated from the round-trip of a request to the master, assuming the frames take as long both ways. The measured delay is then used to follow the time broadcast by the master, the broadcasts of any other module are ignored.
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` of the requesting `Module`
+    /// * `master`: the `u16` bus id of the module of the time master
+    /// * `timeout`: the `u32` time to wait for the answer (in ms)
+    ///
+    /// Returns the estimated offset (in µs).
+    pub fn sync_time(
+        &mut self,
+        mod_id: ModuleHandle,
+        master: u16,
+        timeout: u32,
+    ) -> Result<i64, RequestError> {
+        let sent = self.ticks.micros();
+        let answer = self.request(mod_id, master, Command::TimeSync, &Vec::new(), timeout)?;
+        let received = self.reply_at;
+        self.time
+            .sync(master, sent, &answer.data, received)
+            .ok_or(RequestError::InvalidReply(master))
+    }
+    /// Returns the bus time (in µs)
+    ///
+    /// It is our local time until we synchronize with the time master (see `sync_time`).
+    pub fn bus_time(&mut self) -> u64 {
+        let now = self.ticks.micros();
+        self.time.bus_time(now)
+    }
+    /// Broadcast our time when due.
+    fn send_time(&mut self) {
+        let now = self.ticks.micros();
+        let sender = match self.time.due(now) {
+            Some(sender) => sender,
+            None => return,
+        };
+        let source = match self.registry.get(sender) {
+            Some(module) => module.id,
+            None => {
+                // The sender has been removed.
+                self.time.stop();
+                return;
+            }
+        };
+
+        // The timestamp is read once the bus is ours: waiting for it would make it stale.
+        self.port.lock();
+        let now = self.ticks.micros();
+        let mut msg = Message::broadcast(Command::TimeSync, &clock::encode(now).to_vec());
+        msg.header.source = source;
+        self.sniff(&msg);
+        // Only the other boards need it, a collided broadcast is replaced by the next one.
+        let bytes = msg.to_bytes();
+        if self.port.send(&bytes) {
+            self.count_tx(&msg, bytes.len());
+        } else {
+            self.stats.count_collision();
+        }
+    }
+    /// Local time truncated to 32 bits (in µs, wraps around like `physical::micros`).
+    fn micros(&mut self) -> u32 {
+        self.ticks.micros() as u32
+    }
+    /// Returns the current baudrate of the bus (0 if unknown)
+    pub fn baudrate(&self) -> u32 {
+        self.port.baudrate()
+    }
+    /// Apply the pending baudrate switch when due.
+    fn update_baudrate(&mut self) {
+        let now = self.micros();
+        match self.baudrates.update(now) {
+            Action::Wait | Action::Done => {}
+            Action::Apply(baudrate) => self.port.set_baudrate(baudrate),
+            Action::Confirm(source, baudrate) => {
+                let data = baudrate::confirmation(baudrate);
+                let mut msg = Message::broadcast(Command::SetBaudrate, &data);
+                msg.header.source = source;
+                self.sniff(&msg);
+                // The boards missing it go back to the previous baudrate.
+                let _ = self.write(&msg, true);
+            }
+            Action::Revert(previous) => self.port.set_baudrate(previous),
+        }
+    }
+    /// Measure the throughput of the bus between one of our modules and a target
+    ///
+    /// `count` frames of `size` bytes of data (`Command::DataResult`) are streamed to the target, announced by a `Command::DataRate`. The target counts them and gives back its counts with the time it took to receive them, its `Core` handles it without any module callback.
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` of the sending `Module`
+    /// * `target`: the `u16` bus id of the target module
+    /// * `count`: the number of frames to stream
+    /// * `size`: the data size of each frame (at least 2 bytes for the sequence number)
+    /// * `timeout`: the `u32` time to wait for the counts of the target (in ms)
+    pub fn benchmark(
+        &mut self,
+        mod_id: ModuleHandle,
+        target: u16,
+        count: u16,
+        size: u8,
+        timeout: u32,
+    ) -> Result<BenchmarkReport, RequestError> {
+        let size = cmp::max(size as usize, MIN_FRAME_DATA);
+        let data = benchmark::announce(count, size as u8);
+        let mut start = Message::id(target, Command::DataRate, &data);
+        self.send(mod_id, &mut start).map_err(RequestError::Send)?;
+
+        for seq in 0..count {
+            let mut frame = Message::id(target, Command::DataResult, &benchmark::frame(seq, size));
+            // The frames lost on the way are part of the measure.
+            let _ = self.send(mod_id, &mut frame);
+        }
+
+        let answer = self.request(mod_id, target, Command::DataRate, &Vec::new(), timeout)?;
+        BenchmarkReport::from_answer(count, size, &answer.data)
+            .ok_or(RequestError::InvalidReply(target))
+    }
+    /// List the modules of the bus
+    ///
+    /// Broadcasts a `Command::Identify` and gathers the `Command::Introduction` answers received within `timeout` (see `introduction` for their data). The answers are given back here and never reach the modules callbacks. Our own modules are listed as well if they answer.
+    ///
+    /// # Arguments
+    /// * `mod_id`: the `ModuleHandle` of the requesting `Module`
+    /// * `timeout`: the `u32` time to wait for the answers (in ms of our `TickSource`)
+    pub fn discover(&mut self, mod_id: ModuleHandle, timeout: u32) -> Result<Directory, SendError> {
+        let id = self.registry[mod_id].id;
+        self.discovery.start(id);
+
+        let mut identify = Message::broadcast(Command::Identify, &Vec::new());
+        if let Err(err) = self.send(mod_id, &mut identify) {
+            self.discovery.finish();
+            return Err(err);
+        }
+        let deadline = self.ticks.micros() + timeout as u64 * 1000;
+        loop {
+            self.poll();
+            if self.ticks.micros() >= deadline {
+                break;
+            }
+            physical::ms_delay(1);
+        }
+
+        Ok(self.discovery.finish())
+    }
+    /// Acknowledge a `TargetMode::IdAck` message if it targets one of our `Module`.
+    fn acknowledge(&mut self, msg: &Message) {
+        let id = match self.registry.iter().find(|module| module.id == msg.header.target) {
+            Some(module) => module.id,
+            None => return,
+        };
+
+        let mut ack = Message::id(
+            msg.header.source,
+            Command::Ack,
+            &vec![msg.header.command as u8],
+        );
+        self.reply(id, &mut ack);
+    }
+    /// Handle the protocol messages
+    fn handle_protocol(&mut self, msg: &Message) {
+        match (msg.header.command, msg.header.target_mode) {
+            (Command::ResetDetection, TargetMode::Broadcast) => {
+                for module in self.registry.iter_mut() {
+                    module.id = DEFAULT_ID;
+                }
+                if let Some(ref mut ptp) = self.ptp {
+                    ptp.reset();
+                }
+            }
+            (Command::WatchPtp, TargetMode::Broadcast) => {
+                if let Some(ref mut ptp) = self.ptp {
+                    ptp.poll();
+                }
+            }
+            (Command::GetId, TargetMode::Broadcast) => {
+                // Only the poked board answers.
+                if self.is_poked() {
+                    let count = self.registry.len() as u8;
+                    let mut answer = Message::id(msg.header.source, Command::GetId, &vec![count]);
+                    self.reply(DEFAULT_ID, &mut answer);
+                }
+            }
+            (Command::WriteId, TargetMode::Broadcast) => {
+                // Only the poked board takes the ids.
+                if self.is_poked() && msg.data.len() == 2 {
+                    let id = msg.data[0] as u16 | (msg.data[1] as u16) << 8;
+                    for (i, module) in self.registry.iter_mut().enumerate() {
+                        module.id = id + i as u16;
+                    }
+                    if let Some(ref mut ptp) = self.ptp {
+                        ptp.acknowledge();
+                    }
+
+                    let mut answer = Message::id(msg.header.source, Command::WriteId, &msg.data);
+                    self.reply(id, &mut answer);
+                }
+            }
+            (Command::GetModuleType, _)
+            | (Command::GetStatus, _)
+            | (Command::GetFirmRevision, _)
+            | (Command::GetComRevision, _) => {
+                // Answers use the same command but always carry data.
+                if !msg.data.is_empty() {
+                    return;
+                }
+                // The answers are sent without waiting for the bus: several boards answering a
+                // broadcast (or type, multicast) query at once would collide.
+                match msg.header.target_mode {
+                    TargetMode::Id | TargetMode::IdAck => {}
+                    _ => return,
+                }
+                let revision = self.firm_revision;
+                let answers: Vec<(u16, Vec<u8>)> = self.registry
+                    .iter()
+                    .filter(|module| module.is_target(&msg.header))
+                    .map(|module| (module.id, query(module, msg.header.command, revision)))
+                    .collect();
+
+                for (id, data) in answers {
+                    let mut answer = Message::id(msg.header.source, msg.header.command, &data);
+                    self.reply(id, &mut answer);
+                }
+            }
+            (Command::WriteAlias, TargetMode::Id) | (Command::WriteAlias, TargetMode::IdAck) => {
+                // No answer: the gate may use an IdAck message to make sure it was received.
+                let alias = match str::from_utf8(&msg.data) {
+                    Ok(alias) => alias,
+                    Err(_) => return,
+                };
+                for handle in self.registry.handles() {
+                    let module = &mut self.registry[handle];
+                    if module.id == msg.header.target && module.set_alias(alias) {
+                        if let Some(ref mut storage) = self.alias_storage {
+                            storage.store(handle.index(), alias);
+                        }
+                    }
+                }
+            }
+            (Command::ModuleRemoved, TargetMode::Broadcast) => {
+                let mut transitions = Vec::new();
+                let now = self.micros();
+                self.liveness.handle(msg, now, &mut transitions);
+                self.notify_presence(transitions);
+                self.module_lost(msg.header.source);
+            }
+            (Command::Heartbeat, TargetMode::Broadcast) => {
+                let mut transitions = Vec::new();
+                let now = self.micros();
+                self.liveness.handle(msg, now, &mut transitions);
+                self.presence_changed(transitions);
+            }
+            (Command::TimeSync, TargetMode::Broadcast) => self.time.handle(msg, self.handled_at),
+            (Command::TimeSync, TargetMode::Id) => {
+                let master = match self.time.master() {
+                    Some(sender) => self.registry.get(sender).map(|module| module.id),
+                    None => None,
+                };
+                // Only the module of the master answers.
+                if msg.data.is_empty() && master == Some(msg.header.target) {
+                    let now = self.ticks.micros();
+                    let data = clock::answer(self.handled_at, now);
+                    let mut answer = Message::id(msg.header.source, Command::TimeSync, &data);
+                    self.reply(msg.header.target, &mut answer);
+                }
+            }
+            (Command::PokeNext, TargetMode::Id) => {
+                // Answers use the same command but always carry a branch.
+                if msg.data.is_empty()
+                    && self.registry
+                        .iter()
+                        .any(|module| module.id == msg.header.target)
+                {
+                    let branch = match self.poke_next(msg.header.target) {
+                        Some(branch) => branch as u8,
+                        None => NO_BRANCH,
+                    };
+                    let mut answer =
+                        Message::id(msg.header.source, Command::PokeNext, &vec![branch]);
+                    self.reply(msg.header.target, &mut answer);
+                }
+            }
+            _ => {}
+        }
+    }
+    /// Poke our next branches until a neighbour answers
+    ///
+    /// Returns the branch of the neighbour, `None` once all the branches have been explored.
+    fn poke_next(&mut self, source: u16) -> Option<usize> {
+        // The PTP state is put aside while poking as the neighbours are notified through the Core.
+        let mut ptp = match self.ptp.take() {
+            Some(ptp) => ptp,
+            None => return None,
+        };
+
+        let mut answered = None;
+        while let Some(branch) = ptp.next_branch() {
+            let answer = ptp.poke(branch, || {
+                let mut watch = Message::broadcast(Command::WatchPtp, &Vec::new());
+                self.reply(source, &mut watch);
+            });
+            if answer {
+                answered = Some(branch);
+                break;
+            }
+        }
+
+        self.ptp = Some(ptp);
+        answered
+    }
+    /// Send a `Message` right away, without waiting for the bus to be free
+    ///
+    /// Used to answer from the reception context: the requester is waiting for our answer and thus keeps the bus free for us.
+    fn reply(&mut self, source: u16, msg: &mut Message) {
+        msg.header.source = source;
+        if self.is_remote(&msg.header) {
+            // The requester handles the missing answers (timeout).
+            let _ = self.write(msg, false);
+        }
+        self.deliver(msg, None);
+    }
+    /// Write a frame on the bus, waiting for the bus to be free if `lock` is set.
+    ///
+    /// The frame is sent again after a random delay each time it collides, up to `tx_retries` times.
+    fn write(&mut self, msg: &Message, lock: bool) -> Result<(), SendError> {
+        let bytes = msg.to_bytes();
+        for attempt in 0..(self.tx_retries as u16 + 1) {
+            if attempt > 0 {
+                let delay = self.backoff(msg.header.source, attempt);
+                physical::ms_delay(delay);
+            }
+            if lock {
+                // Wait tx unlock and lock transmission
+                self.port.lock();
+            }
+            if self.port.send(&bytes) {
+                self.count_tx(msg, bytes.len());
+                return Ok(());
+            }
+            self.stats.count_collision();
+        }
+        Err(SendError::Collision)
+    }
+    /// Random delay (in ms) before the `attempt`-th re-emission of a collided frame.
+    ///
+    /// The range doubles with each attempt (up to 64ms). The generator is seeded by the board (see `physical::unique_seed`): boards still sharing the default id do not draw the same delays.
+    fn backoff(&mut self, source: u16, attempt: u16) -> u32 {
+        let mut seed = self.seed ^ ((source as u32) << 16);
+        let delay = physical::backoff(&mut seed, attempt);
+        self.seed = seed;
+        delay
+    }
+    fn count_tx(&mut self, msg: &Message, len: usize) {
+        self.stats.count_tx(msg.header.target_mode, len);
+    }
+    /// Give a `Message` sent by the `Core` to our own modules.
+    fn deliver(&mut self, msg: &Message, from: Option<ModuleHandle>) {
+        // Sniffers also see the frames they would not get from the bus.
+        self.sniff(msg);
+        self.handle(msg.clone(), from);
+    }
+    /// Checks if a message may target modules of other boards.
+    fn is_remote(&self, header: &Header) -> bool {
+        match header.target_mode {
+            // Several boards may share the default id.
+            TargetMode::Id | TargetMode::IdAck => {
+                header.target == DEFAULT_ID
+                    || !self.registry
+                        .iter()
+                        .any(|module| module.id == header.target)
+            }
+            _ => true,
+        }
+    }
+    fn is_poked(&self) -> bool {
+        match self.ptp {
+            Some(ref ptp) => ptp.is_poked(),
+            None => false,
+        }
+    }
+    /// Tell the user a remote module is gone.
+    fn module_lost(&mut self, id: u16) {
+        if let Some(ref mut cb) = self.lost_callback {
+            cb(id);
+        }
+    }
+    /// Wait for the reply from `source` with the given command.
+    ///
+    /// The reply will be caught by the reception instead of being dispatched.
+    fn await_reply(&mut self, source: u16, command: Command) {
+        self.reply = None;
+        self.awaited = Some((source, command));
+    }
+    /// Wait for the reply set up with `await_reply`.
+    ///
+    /// Returns `None` if the reply did not arrive in time (`timeout` ms of our `TickSource`).
+    fn wait_reply(&mut self, timeout: u32) -> Option<Message> {
+        // The polls take time as well: only the clock tells when to give up.
+        let deadline = self.ticks.micros() + timeout as u64 * 1000;
+        loop {
+            self.poll();
+            if self.reply.is_some() {
+                return self.reply.take();
+            }
+            if self.ticks.micros() >= deadline {
+                break;
+            }
+            physical::ms_delay(1);
+        }
+        self.awaited = None;
+        None
+    }
+    /// Keeps the `Message` if it is the awaited reply, gives it back otherwise.
+    fn catch_reply(&mut self, msg: Message) -> Option<Message> {
+        let awaited = self.awaited;
+        match awaited {
+            // Answers are always sent to their requester, never broadcast.
+            Some((source, command))
+                if source == msg.header.source && command == msg.header.command
+                    && msg.header.target_mode != TargetMode::Broadcast =>
+            {
+                self.awaited = None;
+                self.reply_at = self.handled_at;
+                self.reply = Some(msg);
+                None
+            }
+            _ => Some(msg),
+        }
+    }
+}
+
+/// Drives the topology detection through the bus.
+struct BusNetwork<'c, 'a: 'c> {
+    core: &'c mut Core<'a>,
+    mod_id: ModuleHandle,
+}
+
+impl<'c, 'a: 'c> BusNetwork<'c, 'a> {
+    /// Send a request and wait for the answer of `source`.
+    fn request(&mut self, source: u16, msg: &mut Message) -> Option<Message> {
+        self.core.await_reply(source, msg.header.command);
+        // A lost request is handled as a missing answer.
+        let _ = self.core.send(self.mod_id, msg);
+        self.core.wait_reply(DETECTION_TIMEOUT)
+    }
+}
+
+impl<'c, 'a: 'c> Network for BusNetwork<'c, 'a> {
+    fn poke_next(&mut self, id: u16) -> Option<usize> {
+        if id == ROOT_ID {
+            return self.core.poke_next(ROOT_ID);
+        }
+
+        let mut msg = Message::id(id, Command::PokeNext, &Vec::new());
+        match self.request(id, &mut msg) {
+            Some(ref answer) if answer.data.len() == 1 && answer.data[0] != NO_BRANCH => {
+                Some(answer.data[0] as usize)
+            }
+            _ => None,
+        }
+    }
+    fn request_ids(&mut self) -> u16 {
+        let mut msg = Message::broadcast(Command::GetId, &Vec::new());
+        match self.request(DEFAULT_ID, &mut msg) {
+            Some(ref answer) if answer.data.len() == 1 => answer.data[0] as u16,
+            _ => 0,
+        }
+    }
+    fn assign(&mut self, id: u16) -> bool {
+        let mut msg = Message::broadcast(Command::WriteId, &vec![id as u8, (id >> 8) as u8]);
+        self.request(id, &mut msg).is_some()
+    }
+}
+
+/// Answer of a `Module` to a protocol query.
+fn query(module: &Module, command: Command, firm_revision: &str) -> Vec<u8> {
+    match command {
+        Command::GetModuleType => vec![module.mod_type as u8],
+        Command::GetStatus => vec![module.status as u8, (module.status >> 8) as u8],
+        Command::GetFirmRevision => firm_revision.as_bytes().to_vec(),
+        Command::GetComRevision => vec![PROTOCOL_VERSION],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    use self::std::time;
+    use self::std::thread;
+    use self::std::rc::Rc;
+    use self::std::cell::{Cell, RefCell};
+    use self::std::sync::Arc;
+    use self::std::sync::atomic::{AtomicBool, Ordering};
+
+    use directory::introduction;
+    use module::tests::rand_type;
+    use sim_bus::{SimBus, SimPort, SIM_BAUDRATE};
+    use storage::MemoryStorage;
+    use topology::sim::{ptp_wire, SimLine};
+    use transfer;
+    use msg::tests::{rand_command, rand_data, rand_data_size, rand_id};
+    use msg::HEADER_SIZE;
+    use physical::TX_QUEUE_SIZE;
+
+    macro_rules! wait_timeout {
+        ($evt: expr, $dur: expr, $cb: expr) => (
+            let now = time::SystemTime::now();
+            while !$evt.is_set() {
+                let dt = now.elapsed().unwrap();
+                if dt > $dur {
+                    $cb();
+                    break;
+                }
+            }
+        );
+    }
+    #[test]
+    fn fill_source_on_send() {
+        let mut core = Core::new();
+        let mut msg = rand_id_msg();
+
+        let from = rand_id();
+
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, from);
+
+        core.send(m1, &mut msg).unwrap();
+
+        assert_eq!(msg.header.source, from);
+    }
+
+    #[test]
+    fn id_local_loop() {
+        let mut send_msg = rand_id_msg();
+        let gold_msg = send_msg.clone();
 
-    #[test]
-    fn id_local_loop() {
-        let mut send_msg = rand_id_msg();
-        let gold_msg = send_msg.clone();
-
         let (called_tx, called_rx) = Event::new();
 
         let m1_cb = move |msg: Message| {
@@ -216,7 +1552,7 @@ mod tests {
         let m2 = core.create_module("m2", rand_type(), &m2_cb);
         core.set_module_id(m2, diff_id);
 
-        core.send(m1, &mut send_msg);
+        core.send(m2, &mut send_msg).unwrap();
 
         wait_timeout!(called_rx, time::Duration::from_secs(1), || assert!(
             false,
@@ -247,11 +1583,65 @@ mod tests {
 
         let m1 = core.create_module("m1", rand_type(), &m1_cb);
         core.set_module_id(m1, rand_id());
+        // The sender only gets its own broadcast if it asks for.
+        core.set_module_echo(m1, true);
 
         let m2 = core.create_module("m2", rand_type(), &m2_cb);
         core.set_module_id(m2, rand_id());
 
-        core.send(m1, &mut send_msg);
+        core.send(m1, &mut send_msg).unwrap();
+
+        wait_timeout!(called_rx_1, time::Duration::from_secs(1), || assert!(
+            false,
+            "Callback was never called!"
+        ));
+        wait_timeout!(called_rx_2, time::Duration::from_secs(1), || assert!(
+            false,
+            "Callback was never called!"
+        ));
+    }
+    #[test]
+    fn type_dispatch() {
+        let mut send_msg = Message::type_msg(
+            ModuleType::Servo as u16,
+            rand_command(),
+            &rand_data(rand_data_size()),
+        );
+        let gm1 = send_msg.clone();
+        let gm2 = send_msg.clone();
+
+        let (called_tx_1, called_rx_1) = Event::new();
+        let (called_tx_2, called_rx_2) = Event::new();
+
+        let servo1_cb = move |msg: Message| {
+            assert_eq!(msg.header.command, gm1.header.command);
+            assert_eq!(msg.data, gm1.data);
+            called_tx_1.set();
+        };
+        let servo2_cb = move |msg: Message| {
+            assert_eq!(msg.header.command, gm2.header.command);
+            assert_eq!(msg.data, gm2.data);
+            called_tx_2.set();
+        };
+        let button_cb = move |_msg: Message| {
+            assert!(false);
+        };
+        let led_cb = move |_msg: Message| {
+            assert!(false);
+        };
+
+        let mut core = Core::new();
+
+        let servo1 = core.create_module("servo1", ModuleType::Servo, &servo1_cb);
+        core.set_module_id(servo1, 1);
+        let button = core.create_module("button", ModuleType::Button, &button_cb);
+        core.set_module_id(button, 2);
+        let servo2 = core.create_module("servo2", ModuleType::Servo, &servo2_cb);
+        core.set_module_id(servo2, 3);
+        let led = core.create_module("led", ModuleType::RgbLed, &led_cb);
+        core.set_module_id(led, 4);
+
+        core.send(button, &mut send_msg).unwrap();
 
         wait_timeout!(called_rx_1, time::Duration::from_secs(1), || assert!(
             false,
@@ -262,6 +1652,1421 @@ mod tests {
             "Callback was never called!"
         ));
     }
+    #[test]
+    fn type_dispatch_no_match() {
+        let mut send_msg = Message::type_msg(
+            ModuleType::Stepper as u16,
+            rand_command(),
+            &rand_data(rand_data_size()),
+        );
+
+        let m1_cb = move |_msg: Message| {
+            assert!(false);
+        };
+        let m2_cb = move |_msg: Message| {
+            assert!(false);
+        };
+
+        let mut core = Core::new();
+
+        let m1 = core.create_module("m1", ModuleType::Servo, &m1_cb);
+        core.set_module_id(m1, 1);
+        let m2 = core.create_module("m2", ModuleType::Button, &m2_cb);
+        core.set_module_id(m2, 2);
+
+        core.send(m1, &mut send_msg).unwrap();
+    }
+    #[test]
+    fn multicast() {
+        let group = rand_id();
+        let mut send_msg = Message::multicast(group, rand_command(), &rand_data(rand_data_size()));
+        let gm1 = send_msg.clone();
+        let gm3 = send_msg.clone();
+
+        let (called_tx_1, called_rx_1) = Event::new();
+        let (called_tx_3, called_rx_3) = Event::new();
+
+        let m1_cb = move |msg: Message| {
+            assert_eq!(msg.header.command, gm1.header.command);
+            assert_eq!(msg.data, gm1.data);
+            called_tx_1.set();
+        };
+        let m2_cb = move |_msg: Message| {
+            assert!(false);
+        };
+        let m3_cb = move |msg: Message| {
+            assert_eq!(msg.header.command, gm3.header.command);
+            assert_eq!(msg.data, gm3.data);
+            called_tx_3.set();
+        };
+
+        let mut core = Core::new();
+
+        let m1 = core.create_module("m1", rand_type(), &m1_cb);
+        core.set_module_id(m1, 1);
+        core.join_group(m1, group);
+
+        let m2 = core.create_module("m2", rand_type(), &m2_cb);
+        core.set_module_id(m2, 2);
+
+        let m3 = core.create_module("m3", rand_type(), &m3_cb);
+        core.set_module_id(m3, 3);
+        core.join_group(m3, group);
+
+        core.send(m2, &mut send_msg).unwrap();
+
+        wait_timeout!(called_rx_1, time::Duration::from_secs(1), || assert!(
+            false,
+            "Callback was never called!"
+        ));
+        wait_timeout!(called_rx_3, time::Duration::from_secs(1), || assert!(
+            false,
+            "Callback was never called!"
+        ));
+    }
+    #[test]
+    fn multicast_after_leave() {
+        let group = rand_id();
+        let mut send_msg = Message::multicast(group, rand_command(), &rand_data(rand_data_size()));
+
+        let m1_cb = move |_msg: Message| {
+            assert!(false);
+        };
+
+        let mut core = Core::new();
+
+        let m1 = core.create_module("m1", rand_type(), &m1_cb);
+        core.set_module_id(m1, 1);
+        core.join_group(m1, group);
+        core.leave_group(m1, group);
+
+        core.send(m1, &mut send_msg).unwrap();
+    }
+    #[test]
+    fn reliable_delivery() {
+        let mut send_msg = Message::id(2, rand_command(), &rand_data(rand_data_size()));
+        let gold_msg = send_msg.clone();
+
+        let received = Rc::new(Cell::new(0));
+        let m2_received = received.clone();
+
+        let m1_cb = move |_msg: Message| {
+            assert!(false);
+        };
+        let m2_cb = move |msg: Message| {
+            assert_eq!(msg.header.target_mode, TargetMode::IdAck);
+            assert_eq!(msg.header.command, gold_msg.header.command);
+            assert_eq!(msg.data, gold_msg.data);
+            m2_received.set(m2_received.get() + 1);
+        };
+
+        let mut core = Core::new();
+
+        let m1 = core.create_module("m1", rand_type(), &m1_cb);
+        core.set_module_id(m1, 1);
+        let m2 = core.create_module("m2", rand_type(), &m2_cb);
+        core.set_module_id(m2, 2);
+
+        assert_eq!(core.send_reliable(m1, &mut send_msg, 3, 10), Ok(()));
+        assert_eq!(received.get(), 1);
+    }
+    #[test]
+    fn reliable_delivery_without_target() {
+        let mut send_msg = Message::id(42, rand_command(), &rand_data(rand_data_size()));
+
+        let sniffed = Rc::new(Cell::new(0));
+        let sniffer_sniffed = sniffed.clone();
+
+        let sniffer_cb = move |msg: Message| {
+            assert_eq!(msg.header.target, 42);
+            sniffer_sniffed.set(sniffer_sniffed.get() + 1);
+        };
+
+        let mut core = Core::new();
+
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 1);
+        let sniffer = core.create_module("sniffer", ModuleType::Sniffer, &sniffer_cb);
+        core.set_module_id(sniffer, 2);
+
+        assert_eq!(
+            core.send_reliable(m1, &mut send_msg, 2, 1),
+            Err(DeliveryError::NoAck(42))
+        );
+        assert_eq!(sniffed.get(), 3);
+    }
+    #[test]
+    fn adopt_ids_when_poked() {
+        let mut core = Core::new();
+        let mut gate = probe(&mut core);
+
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        let m2 = core.create_module("m2", rand_type(), |_| {});
+        core.set_module_id(m1, 42);
+
+        let (mut neighbour, line) = ptp_wire();
+        core.set_ptp_lines(sim_lines(vec![SimLine::open(), line]));
+
+        inject(&mut core, ROOT_ID, Message::broadcast(Command::ResetDetection, &vec![]));
+        assert_eq!(core.module_id(m1), DEFAULT_ID);
+
+        // The neighbour pokes us.
+        neighbour.pull_low();
+        inject(&mut core, ROOT_ID, Message::broadcast(Command::WatchPtp, &vec![]));
+        neighbour.release();
+        assert!(neighbour.is_low());
+
+        inject(&mut core, ROOT_ID, Message::broadcast(Command::GetId, &vec![]));
+        let answers = read_frames(&mut gate);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].header.source, DEFAULT_ID);
+        assert_eq!(answers[0].data, vec![2]);
+
+        inject(&mut core, ROOT_ID, Message::broadcast(Command::WriteId, &vec![10, 0]));
+        let answers = read_frames(&mut gate);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].header.source, 10);
+        assert_eq!(answers[0].header.command, Command::WriteId);
+
+        assert_eq!(core.module_id(m1), 10);
+        assert_eq!(core.module_id(m2), 11);
+        assert!(!neighbour.is_low());
+    }
+    #[test]
+    fn ignore_ids_when_not_poked() {
+        let mut core = Core::new();
+        let mut gate = probe(&mut core);
+
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 42);
+
+        let (_neighbour, line) = ptp_wire();
+        core.set_ptp_lines(sim_lines(vec![line]));
+
+        inject(&mut core, ROOT_ID, Message::broadcast(Command::GetId, &vec![]));
+        assert!(read_frames(&mut gate).is_empty());
+
+        inject(&mut core, ROOT_ID, Message::broadcast(Command::WriteId, &vec![10, 0]));
+        assert_eq!(core.module_id(m1), 42);
+    }
+    #[test]
+    fn poke_next_on_request() {
+        let mut core = Core::new();
+        let mut gate = probe(&mut core);
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+
+        let (mut parent, parent_line) = ptp_wire();
+        let (mut child, child_line) = ptp_wire();
+        core.set_ptp_lines(sim_lines(vec![parent_line, child_line]));
+
+        // Detection of the core by its parent.
+        parent.pull_low();
+        inject(&mut core, ROOT_ID, Message::broadcast(Command::WatchPtp, &vec![]));
+        parent.release();
+        inject(&mut core, ROOT_ID, Message::broadcast(Command::WriteId, &vec![2, 0]));
+        assert_eq!(core.module_id(m1), 2);
+
+        // The child answers the poke by holding its line.
+        read_frames(&mut gate);
+        child.pull_low();
+        inject(&mut core, ROOT_ID, Message::id(2, Command::PokeNext, &vec![]));
+        // The child is told to watch its line before the answer.
+        let frames = read_frames(&mut gate);
+        assert_eq!(frames[0].header.command, Command::WatchPtp);
+        assert_eq!(frames.last().unwrap().header.command, Command::PokeNext);
+        assert_eq!(frames.last().unwrap().data, vec![1]);
+        child.release();
+
+        inject(&mut core, ROOT_ID, Message::id(2, Command::PokeNext, &vec![]));
+        let frames = read_frames(&mut gate);
+        assert_eq!(frames.last().unwrap().data, vec![NO_BRANCH]);
+    }
+    #[test]
+    fn gate_detection() {
+        let mut core = Core::new();
+
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        let m2 = core.create_module("m2", rand_type(), |_| {});
+        core.set_ptp_lines(sim_lines(vec![SimLine::open(), SimLine::open()]));
+
+        let topology = core.detect_modules(m1).unwrap();
+
+        assert_eq!(topology.len(), 1);
+        assert_eq!(topology.root().id, ROOT_ID);
+        assert_eq!(core.module_id(m1), ROOT_ID);
+        assert_eq!(core.module_id(m2), ROOT_ID + 1);
+    }
+    #[test]
+    fn protocol_queries() {
+        let mut core = Core::new();
+        let mut gate = probe(&mut core);
+
+        let m1 = core.create_module("m1", ModuleType::Servo, |_| {
+            assert!(false);
+        });
+        core.set_module_id(m1, 2);
+        core.set_module_status(m1, 0x1234);
+        core.set_firm_revision("1.2.3");
+
+        let gold = [
+            (Command::GetModuleType, vec![ModuleType::Servo as u8]),
+            (Command::GetStatus, vec![0x34, 0x12]),
+            (Command::GetFirmRevision, "1.2.3".as_bytes().to_vec()),
+            (Command::GetComRevision, vec![PROTOCOL_VERSION]),
+        ];
+
+        for &(command, ref data) in gold.iter() {
+            inject(&mut core, ROOT_ID, Message::id(2, command, &vec![]));
+
+            let answers = read_frames(&mut gate);
+            assert_eq!(answers.len(), 1);
+            assert_eq!(answers[0].header.source, 2);
+            assert_eq!(answers[0].header.target, ROOT_ID);
+            assert_eq!(answers[0].header.command, command);
+            assert_eq!(answers[0].data, *data);
+        }
+    }
+    #[test]
+    fn protocol_queries_by_type() {
+        let mut core = Core::new();
+        let mut gate = probe(&mut core);
+
+        let servo1 = core.create_module("servo1", ModuleType::Servo, |_| {});
+        core.set_module_id(servo1, 2);
+        let button = core.create_module("button", ModuleType::Button, |_| {});
+        core.set_module_id(button, 3);
+
+        // Only the queries sent to an id are answered.
+        inject(
+            &mut core,
+            ROOT_ID,
+            Message::type_msg(ModuleType::Servo as u16, Command::GetModuleType, &vec![]),
+        );
+        inject(&mut core, ROOT_ID, Message::broadcast(Command::GetStatus, &vec![]));
+        assert!(read_frames(&mut gate).is_empty());
+
+        inject(&mut core, ROOT_ID, Message::id(3, Command::GetModuleType, &vec![]));
+        let answers = read_frames(&mut gate);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].header.source, 3);
+        assert_eq!(answers[0].data, vec![ModuleType::Button as u8]);
+    }
+    #[test]
+    #[should_panic]
+    fn empty_firm_revision() {
+        let mut core = Core::new();
+        core.set_firm_revision("");
+    }
+    static LONG_REVISION: [u8; 256] = [b'1'; 256];
+    #[test]
+    fn longest_firm_revision() {
+        let mut core = Core::new();
+        let mut gate = probe(&mut core);
+
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 2);
+        core.set_firm_revision(str::from_utf8(&LONG_REVISION[..255]).unwrap());
+
+        inject(&mut core, ROOT_ID, Message::id(2, Command::GetFirmRevision, &vec![]));
+        let answers = read_frames(&mut gate);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].data, LONG_REVISION[..255].to_vec());
+    }
+    #[test]
+    #[should_panic]
+    fn too_long_firm_revision() {
+        let mut core = Core::new();
+        core.set_firm_revision(str::from_utf8(&LONG_REVISION).unwrap());
+    }
+    #[test]
+    fn write_alias() {
+        let mut core = Core::new();
+
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 2);
+        let m2 = core.create_module("m2", rand_type(), |_| {});
+        core.set_module_id(m2, 3);
+
+        core.set_alias_storage(Box::new(MemoryStorage::new()));
+
+        inject(
+            &mut core,
+            ROOT_ID,
+            Message::id(3, Command::WriteAlias, &"left_leg".as_bytes().to_vec()),
+        );
+        assert_eq!(core.module_alias(m1), "m1");
+        assert_eq!(core.module_alias(m2), "left_leg");
+
+        // Invalid aliases are ignored.
+        inject(
+            &mut core,
+            ROOT_ID,
+            Message::id(3, Command::WriteAlias, &"a_much_too_long_alias".as_bytes().to_vec()),
+        );
+        inject(
+            &mut core,
+            ROOT_ID,
+            Message::id(3, Command::WriteAlias, &vec![0xFF, 0xFE]),
+        );
+        // A line break would shift the aliases of a `FileStorage`.
+        inject(
+            &mut core,
+            ROOT_ID,
+            Message::id(3, Command::WriteAlias, &"left\nleg".as_bytes().to_vec()),
+        );
+        assert_eq!(core.module_alias(m2), "left_leg");
+
+        assert_eq!(
+            core.alias_storage.as_ref().unwrap().load(m2.index()),
+            Some(String::from("left_leg"))
+        );
+    }
+    #[test]
+    fn stored_alias_survives_reboot() {
+        let mut storage = MemoryStorage::new();
+        storage.store(1, "right_leg");
+
+        // Storage set before the creation of the modules.
+        let mut core = Core::new();
+        core.set_alias_storage(Box::new(storage));
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        let m2 = core.create_module("m2", rand_type(), |_| {});
+
+        assert_eq!(core.module_alias(m1), "m1");
+        assert_eq!(core.module_alias(m2), "right_leg");
+
+        // Storage set after the creation of the modules.
+        let mut storage = MemoryStorage::new();
+        storage.store(0, "head");
+
+        let mut core = Core::new();
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_alias_storage(Box::new(storage));
+
+        assert_eq!(core.module_alias(m1), "head");
+    }
+    #[test]
+    fn removed_module_alias() {
+        let mut core = Core::new();
+        core.set_alias_storage(Box::new(MemoryStorage::new()));
+
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 2);
+        inject(
+            &mut core,
+            ROOT_ID,
+            Message::id(2, Command::WriteAlias, &"left_leg".as_bytes().to_vec()),
+        );
+        assert_eq!(core.module_alias(m1), "left_leg");
+
+        // The new module takes the slot of the removed one, not its alias.
+        core.remove_module(m1);
+        let m2 = core.create_module("m2", rand_type(), |_| {});
+        assert_eq!(m2.index(), m1.index());
+        assert_eq!(core.module_alias(m2), "m2");
+    }
+    #[test]
+    fn remove_module() {
+        let send_msg = Message::broadcast(rand_command(), &rand_data(rand_data_size()));
+
+        let received = Rc::new(Cell::new(0));
+        let m2_received = received.clone();
+
+        let m1_cb = move |_msg: Message| {
+            assert!(false);
+        };
+        let m2_cb = move |_msg: Message| {
+            m2_received.set(m2_received.get() + 1);
+        };
+
+        let mut core = Core::new();
+
+        let m1 = core.create_module("m1", rand_type(), &m1_cb);
+        let m2 = core.create_module("m2", rand_type(), &m2_cb);
+        core.set_module_id(m2, 2);
+
+        assert!(core.remove_module(m1));
+        assert!(!core.has_module(m1));
+        assert!(!core.remove_module(m1));
+
+        inject(&mut core, 7, send_msg);
+        assert_eq!(received.get(), 1);
+
+        // The new module reuses the slot of m1 without reviving its handle.
+        let m3 = core.create_module("m3", rand_type(), |_| {});
+        assert_eq!(m3.index(), m1.index());
+        assert!(!core.has_module(m1));
+        assert_eq!(core.module_alias(m3), "m3");
+        assert_eq!(core.module_id(m2), 2);
+    }
+    #[test]
+    #[should_panic]
+    fn stale_handle() {
+        let mut core = Core::new();
+
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.remove_module(m1);
+        core.create_module("m2", rand_type(), |_| {});
+
+        core.set_module_id(m1, 2);
+    }
+    #[test]
+    fn lost_modules() {
+        let lost: Rc<RefCell<Vec<u16>>> = Rc::new(RefCell::new(Vec::new()));
+        let cb_lost = lost.clone();
+
+        let mut core = Core::new();
+        core.set_module_lost_callback(move |id: u16| cb_lost.borrow_mut().push(id));
+
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 1);
+
+        // A remote module announces its removal.
+        inject(&mut core, 7, Message::broadcast(Command::ModuleRemoved, &vec![]));
+        assert_eq!(*lost.borrow(), vec![7]);
+
+        // A missing acknowledgment is only a delivery error.
+        let mut msg = Message::id(42, rand_command(), &rand_data(rand_data_size()));
+        assert!(core.send_reliable(m1, &mut msg, 1, 1).is_err());
+        assert_eq!(*lost.borrow(), vec![7]);
+
+        // Removing a module without bus id stays silent.
+        let m2 = core.create_module("m2", rand_type(), |_| {});
+        core.remove_module(m2);
+        assert_eq!(*lost.borrow(), vec![7]);
+    }
+    #[test]
+    fn independent_cores() {
+        // Callbacks may borrow local state living longer than the cores.
+        let received = Cell::new(0);
+        let cb = |_: Message| received.set(received.get() + 1);
+
+        let mut core1 = Core::new();
+        let mut core2 = Core::new();
+
+        let m1 = core1.create_module("m1", rand_type(), &cb);
+        core1.set_module_id(m1, 1);
+        let m2 = core2.create_module("m2", rand_type(), &cb);
+        core2.set_module_id(m2, 1);
+
+        inject(&mut core1, 3, Message::id(1, rand_command(), &rand_data(rand_data_size())));
+        assert_eq!(received.get(), 1);
+
+        assert!(core1.remove_module(m1));
+        assert!(core2.has_module(m2));
+        assert_eq!(core2.module_alias(m2), "m2");
+    }
+    #[test]
+    fn reply_to_source() {
+        let received: Rc<RefCell<Vec<Message>>> = Rc::new(RefCell::new(Vec::new()));
+        let m1_received = received.clone();
+
+        let m1_cb = move |msg: Message| m1_received.borrow_mut().push(msg);
+        let m2_cb = |msg: Message| match msg.header.command {
+            Command::GetState => Some(Message::reply(Command::PublishState, &vec![42])),
+            _ => None,
+        };
+        let m3_cb = |_msg: Message| Some(Message::reply(Command::PublishState, &vec![0]));
+
+        let mut core = Core::new();
+
+        let m1 = core.create_module("m1", rand_type(), m1_cb);
+        core.set_module_id(m1, 1);
+        let m2 = core.create_module_with_reply("m2", rand_type(), m2_cb);
+        core.set_module_id(m2, 2);
+        let m3 = core.create_module_with_reply("m3", rand_type(), m3_cb);
+        core.set_module_id(m3, 3);
+
+        let mut msg = Message::id(2, Command::GetState, &vec![]);
+        core.send(m1, &mut msg).unwrap();
+        // Delivered by the next poll
+        assert!(received.borrow().is_empty());
+        core.poll();
+
+        assert_eq!(received.borrow().len(), 1);
+        let answer = received.borrow()[0].clone();
+        assert_eq!(answer.header.source, 2);
+        assert_eq!(answer.header.target, 1);
+        assert_eq!(answer.header.target_mode, TargetMode::Id);
+        assert_eq!(answer.header.command, Command::PublishState);
+        assert_eq!(answer.data, vec![42]);
+
+        // No reply
+        let mut msg = Message::id(2, Command::SetState, &vec![1]);
+        core.send(m1, &mut msg).unwrap();
+        core.poll();
+        assert_eq!(received.borrow().len(), 1);
+    }
+    #[test]
+    fn local_ping_pong() {
+        let received = Rc::new(Cell::new(0));
+        let m1_received = received.clone();
+        let m2_received = received.clone();
+
+        let mut core = Core::new();
+        // Both modules answer whatever they get.
+        let m1 = core.create_module_with_reply("m1", rand_type(), move |_| {
+            m1_received.set(m1_received.get() + 1);
+            Some(Message::reply(Command::PublishState, &vec![1]))
+        });
+        core.set_module_id(m1, 1);
+        let m2 = core.create_module_with_reply("m2", rand_type(), move |_| {
+            m2_received.set(m2_received.get() + 1);
+            Some(Message::reply(Command::PublishState, &vec![2]))
+        });
+        core.set_module_id(m2, 2);
+
+        let mut msg = Message::id(2, Command::GetState, &vec![]);
+        core.send(m1, &mut msg).unwrap();
+        assert_eq!(received.get(), 1);
+        // One answer per poll
+        for i in 0..1000 {
+            core.poll();
+            assert_eq!(received.get(), i + 2);
+        }
+    }
+    #[test]
+    fn request() {
+        let m1_cb = |_msg: Message| {
+            assert!(false);
+        };
+        let m2_cb = |msg: Message| match msg.header.command {
+            Command::GetState => Some(Message::reply(Command::PublishState, &vec![42])),
+            _ => None,
+        };
+
+        let mut core = Core::new();
+
+        let m1 = core.create_module("m1", rand_type(), m1_cb);
+        core.set_module_id(m1, 1);
+        let m2 = core.create_module_with_reply("m2", ModuleType::Servo, m2_cb);
+        core.set_module_id(m2, 2);
+
+        let answer = core.request(m1, 2, Command::GetState, &vec![], 10).unwrap();
+        assert_eq!(answer.header.source, 2);
+        assert_eq!(answer.header.command, Command::PublishState);
+        assert_eq!(answer.data, vec![42]);
+
+        // Protocol queries are answered by the Core.
+        let answer = core.request(m1, 2, Command::GetModuleType, &vec![], 10).unwrap();
+        assert_eq!(answer.data, vec![ModuleType::Servo as u8]);
+
+        assert_eq!(
+            core.request(m1, 42, Command::GetState, &vec![], 1),
+            Err(RequestError::Timeout(42))
+        );
+        assert_eq!(
+            core.request(m1, 2, Command::LedColor, &vec![1, 2, 3], 1),
+            Err(RequestError::NoReplyExpected(Command::LedColor))
+        );
+    }
+    #[test]
+    fn promiscuous_sniffer() {
+        let mut frames = Vec::new();
+        let mut errors = Vec::new();
+        {
+            let mut core = Core::new();
+
+            let m1 = core.create_module("m1", ModuleType::Servo, |_| {});
+            core.set_module_id(m1, 1);
+            let sniffer = core.create_sniffer(
+                "sniffer",
+                |msg: Message| frames.push((msg.header.source, msg.header.command)),
+                |error: ParsingError| errors.push(error),
+            );
+            core.set_module_id(sniffer, 2);
+
+            let mut msg = Message::type_msg(ModuleType::Stepper as u16, Command::LedColor, &vec![]);
+            core.send(m1, &mut msg).unwrap();
+            let mut msg = Message::multicast(7, Command::ServoSpeed, &vec![1]);
+            core.send(m1, &mut msg).unwrap();
+            let mut msg = Message::id(42, Command::ServoPosition, &vec![1]);
+            core.send(m1, &mut msg).unwrap();
+            // Reliable delivery: both the message and its ACK are sniffed.
+            let mut msg = Message::id(1, Command::SetCompliant, &vec![1]);
+            assert_eq!(core.send_reliable(m1, &mut msg, 0, 1), Ok(()));
+            // Protocol query and its answer.
+            inject(&mut core, 3, Message::id(1, Command::GetModuleType, &vec![]));
+
+            // Corrupted CRC
+            let mut bytes = Message::broadcast(Command::Identify, &vec![]).to_bytes();
+            let last = bytes.len() - 1;
+            bytes[last] = !bytes[last];
+            for byte in bytes {
+                core.receive(byte);
+            }
+            // Unknown protocol
+            core.receive(0xFF);
+            for _ in 1..HEADER_SIZE {
+                core.receive(0);
+            }
+        }
+        assert_eq!(
+            frames,
+            vec![
+                (1, Command::LedColor),
+                (1, Command::ServoSpeed),
+                (1, Command::ServoPosition),
+                (1, Command::SetCompliant),
+                (1, Command::Ack),
+                (3, Command::GetModuleType),
+                (1, Command::GetModuleType),
+            ]
+        );
+        assert_eq!(errors.len(), 2);
+        match errors[0] {
+            ParsingError::InvalidCrc(_) => {}
+            ref e => panic!("unexpected {:?}", e),
+        }
+        assert_eq!(errors[1], ParsingError::InvalidProtocol(0x0F));
+    }
+    #[test]
+    fn stateful_callbacks() {
+        let mut received = Vec::new();
+        let mut lost = 0;
+        {
+            let mut core = Core::new();
+
+            let m1 = core.create_module("m1", rand_type(), |msg: Message| received.push(msg.data));
+            core.set_module_id(m1, 2);
+            core.set_module_lost_callback(|_| lost += 1);
+
+            inject(&mut core, 1, Message::id(2, rand_command(), &vec![1]));
+            inject(&mut core, 1, Message::id(2, rand_command(), &vec![2]));
+
+            inject(&mut core, 7, Message::broadcast(Command::ModuleRemoved, &vec![]));
+        }
+        assert_eq!(received, vec![vec![1], vec![2]]);
+        assert_eq!(lost, 1);
+    }
+    #[test]
+    fn local_delivery_skips_bus() {
+        let received: Rc<RefCell<Vec<(u16, Command)>>> = Rc::new(RefCell::new(Vec::new()));
+        let m1_received = received.clone();
+        let m2_received = received.clone();
+
+        let mut core = Core::new();
+        let mut bus = probe(&mut core);
+
+        let m1 = core.create_module("m1", rand_type(), move |msg: Message| {
+            m1_received.borrow_mut().push((1, msg.header.command))
+        });
+        core.set_module_id(m1, 1);
+        let m2 = core.create_module("m2", rand_type(), move |msg: Message| {
+            m2_received.borrow_mut().push((2, msg.header.command))
+        });
+        core.set_module_id(m2, 2);
+
+        // Local target: no bus time.
+        let mut msg = Message::id(2, Command::SetState, &vec![1]);
+        core.send(m1, &mut msg).unwrap();
+        assert!(read_frames(&mut bus).is_empty());
+
+        // Remote target
+        let mut msg = Message::id(42, Command::SetState, &vec![2]);
+        core.send(m1, &mut msg).unwrap();
+        let frames = read_frames(&mut bus);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].header.source, 1);
+        assert_eq!(frames[0].header.target, 42);
+
+        // Local and remote targets, the sender excluded.
+        let mut msg = Message::broadcast(Command::PublishState, &vec![3]);
+        core.send(m1, &mut msg).unwrap();
+        assert_eq!(read_frames(&mut bus).len(), 1);
+
+        // Acknowledged without the bus.
+        let mut msg = Message::id(2, Command::SetCompliant, &vec![4]);
+        assert_eq!(core.send_reliable(m1, &mut msg, 0, 0), Ok(()));
+        assert!(read_frames(&mut bus).is_empty());
+
+        assert_eq!(
+            *received.borrow(),
+            vec![
+                (2, Command::SetState),
+                (2, Command::PublishState),
+                (2, Command::SetCompliant),
+            ]
+        );
+    }
+    #[test]
+    fn remote_delivery() {
+        let received = Rc::new(Cell::new(0));
+        let m2_received = received.clone();
+
+        let bus = SimBus::new();
+
+        let mut core1 = connected(&bus);
+        let m1 = core1.create_module("m1", rand_type(), |_| {});
+        core1.set_module_id(m1, 1);
+
+        let mut core2 = connected(&bus);
+        let m2 = core2.create_module("m2", rand_type(), move |msg: Message| {
+            assert_eq!(msg.header.source, 1);
+            m2_received.set(m2_received.get() + 1);
+        });
+        core2.set_module_id(m2, 2);
+
+        let mut msg = Message::id(2, rand_command(), &rand_data(rand_data_size()));
+        core1.send(m1, &mut msg).unwrap();
+        assert_eq!(received.get(), 0);
+
+        core2.poll();
+        assert_eq!(received.get(), 1);
+    }
+    #[test]
+    fn bus_stats() {
+        let bus = SimBus::new();
+        let mut gate = bus.connect();
+
+        let mut core = connected(&bus);
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 2);
+        let m2 = core.create_module("m2", rand_type(), |_| {});
+        core.set_module_id(m2, 3);
+
+        // Received frames
+        let mut msg = Message::broadcast(rand_command(), &vec![1, 2]);
+        msg.header.source = ROOT_ID;
+        let valid = msg.to_bytes();
+        gate.send(&valid);
+        let mut corrupted = valid.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] = !corrupted[last];
+        gate.send(&corrupted);
+        // Interrupted frame
+        gate.send(&valid[..HEADER_SIZE + 1]);
+        core.poll();
+
+        // Sent frames: only the remote one uses the bus.
+        let mut msg = Message::id(3, rand_command(), &vec![]);
+        core.send(m1, &mut msg).unwrap();
+        let mut msg = Message::id(42, rand_command(), &vec![]);
+        core.send(m1, &mut msg).unwrap();
+
+        let stats = core.stats();
+        assert_eq!(
+            stats.bytes_rx as usize,
+            valid.len() + corrupted.len() + HEADER_SIZE + 1
+        );
+        assert_eq!(stats.frames_rx.broadcast, 1);
+        assert_eq!(stats.frames_rx.total(), 1);
+        assert_eq!(stats.crc_errors, 1);
+        assert_eq!(stats.timeouts, 1);
+        assert_eq!(stats.errors(), 2);
+        // The broadcast reached both modules, the local message m2 only.
+        assert_eq!(stats.dispatched, 3);
+        assert_eq!(stats.frames_tx.id, 1);
+        assert_eq!(stats.frames_tx.total(), 1);
+        assert_eq!(stats.bytes_tx as usize, msg.to_bytes().len());
+
+        core.reset_stats();
+        assert_eq!(core.stats(), Stats::default());
+    }
+    #[test]
+    fn error_callback() {
+        let mut errors = Vec::new();
+        {
+            let bus = SimBus::new();
+            let mut gate = bus.connect();
+
+            let mut core = connected(&bus);
+            core.set_error_callback(|error: BusError| errors.push(error));
+
+            let valid = Message::broadcast(rand_command(), &vec![1, 2]).to_bytes();
+            let mut corrupted = valid.clone();
+            let last = corrupted.len() - 1;
+            corrupted[last] = !corrupted[last];
+            gate.send(&corrupted);
+            let mut unknown_mode = valid.clone();
+            unknown_mode[2] |= 0b0000_0111;
+            gate.send(&unknown_mode);
+            gate.send(&valid[..3]);
+            gate.send(&valid);
+            core.poll();
+        }
+        assert_eq!(errors.len(), 3);
+        match errors[0] {
+            BusError::Parsing(ParsingError::InvalidCrc((computed, received))) => {
+                assert_ne!(computed, received)
+            }
+            ref e => panic!("unexpected {:?}", e),
+        }
+        assert_eq!(
+            errors[1],
+            BusError::Parsing(ParsingError::InvalidTargetMode(0b0000_0111))
+        );
+        assert_eq!(errors[2], BusError::Timeout(3));
+    }
+    #[test]
+    fn non_blocking_send() {
+        let received = Rc::new(Cell::new(0));
+        let m2_received = received.clone();
+
+        let mut core = Core::new();
+        let mut bus = probe(&mut core);
+
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 1);
+        let m2 = core.create_module("m2", rand_type(), move |_| {
+            m2_received.set(m2_received.get() + 1)
+        });
+        core.set_module_id(m2, 2);
+
+        let data = vec![0; 100];
+        let frame_size = Message::id(42, rand_command(), &data).to_bytes().len();
+        let fitting = TX_QUEUE_SIZE / frame_size;
+        for _ in 0..fitting {
+            let mut msg = Message::id(42, rand_command(), &data);
+            assert_eq!(core.try_send(m1, &mut msg), Ok(()));
+        }
+        let mut msg = Message::id(42, rand_command(), &data);
+        assert_eq!(core.try_send(m1, &mut msg), Err(SendError::WouldBlock));
+
+        // Local modules do not need the queue.
+        let mut msg = Message::id(2, rand_command(), &data);
+        assert_eq!(core.try_send(m1, &mut msg), Ok(()));
+        assert_eq!(received.get(), 1);
+
+        // The queue is sent in the background.
+        assert!(read_frames(&mut bus).is_empty());
+        core.poll();
+        assert_eq!(read_frames(&mut bus).len(), fitting);
+
+        let mut msg = Message::id(42, rand_command(), &data);
+        assert_eq!(core.try_send(m1, &mut msg), Ok(()));
+    }
+    #[test]
+    fn priority_under_load() {
+        let mut core = Core::new();
+        let mut bus = probe(&mut core);
+
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 1);
+
+        // A stream of bulk data fills its share of the queue.
+        let mut streamed = 0;
+        loop {
+            let mut msg = Message::broadcast(Command::PublishState, &vec![0; 50]);
+            match core.enqueue(m1, &mut msg, Priority::Bulk) {
+                Ok(()) => streamed += 1,
+                Err(e) => {
+                    assert_eq!(e, SendError::WouldBlock);
+                    break;
+                }
+            }
+        }
+        assert!(streamed > 2);
+
+        // Urgent messages still find room and jump ahead.
+        let mut msg = Message::broadcast(Command::ServoPosition, &vec![1]);
+        assert_eq!(core.enqueue(m1, &mut msg, Priority::Normal), Ok(()));
+        let mut msg = Message::broadcast(Command::SetCompliant, &vec![1]);
+        assert_eq!(core.enqueue(m1, &mut msg, Priority::High), Ok(()));
+        let mut msg = Message::broadcast(Command::StepperStop, &vec![]);
+        assert_eq!(core.enqueue(m1, &mut msg, Priority::Emergency), Ok(()));
+
+        core.poll();
+        let commands: Vec<Command> = read_frames(&mut bus)
+            .iter()
+            .map(|frame| frame.header.command)
+            .collect();
+        assert_eq!(commands.len(), streamed + 3);
+        assert_eq!(
+            commands[..3].to_vec(),
+            vec![
+                Command::StepperStop,
+                Command::SetCompliant,
+                Command::ServoPosition,
+            ]
+        );
+        assert!(commands[3..].iter().all(|c| *c == Command::PublishState));
+    }
+    #[test]
+    fn collision_retries() {
+        let bus = SimBus::new();
+        let mut observer = bus.connect();
+
+        let mut core = connected(&bus);
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 2);
+
+        // Sent again until it goes through.
+        let mut msg = Message::id(42, rand_command(), &vec![1, 2]);
+        bus.collide(2);
+        assert_eq!(core.send(m1, &mut msg), Ok(()));
+        assert_eq!(read_frames(&mut observer), vec![msg.clone()]);
+
+        let stats = core.stats();
+        assert_eq!(stats.collisions, 2);
+        assert_eq!(stats.frames_tx.total(), 1);
+
+        // Given up once the retries are exhausted.
+        core.set_collision_retries(1);
+        bus.collide(2);
+        assert_eq!(core.send(m1, &mut msg), Err(SendError::Collision));
+        assert!(read_frames(&mut observer).is_empty());
+        assert_eq!(core.stats().collisions, 4);
+        assert_eq!(core.stats().frames_tx.total(), 1);
+    }
+    #[test]
+    fn large_messages() {
+        let received: Rc<RefCell<Vec<Message>>> = Rc::new(RefCell::new(Vec::new()));
+        let large_received = received.clone();
+        let dispatched = Rc::new(Cell::new(0));
+        let m3_dispatched = dispatched.clone();
+
+        let bus = SimBus::new();
+
+        let mut core1 = connected(&bus);
+        let m1 = core1.create_module("m1", rand_type(), |_| {});
+        core1.set_module_id(m1, 2);
+
+        let mut core2 = connected(&bus);
+        let m3 = core2.create_module("m3", rand_type(), move |_| {
+            m3_dispatched.set(m3_dispatched.get() + 1)
+        });
+        core2.set_module_id(m3, 3);
+        core2.set_large_message_callback(move |msg| large_received.borrow_mut().push(msg));
+
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut msg = Message::id(3, rand_command(), &data);
+        core1.send_large(m1, &mut msg).unwrap();
+        core2.poll();
+
+        assert_eq!(received.borrow().len(), 1);
+        let rebuilt = received.borrow()[0].clone();
+        assert_eq!(rebuilt.header.source, 2);
+        assert_eq!(rebuilt.header.command, msg.header.command);
+        assert_eq!(rebuilt.data, data);
+        // The fragments never reach the modules.
+        assert_eq!(dispatched.get(), 0);
+
+        // Other targets ignore the transfer.
+        let mut msg = Message::id(4, rand_command(), &data);
+        core1.send_large(m1, &mut msg).unwrap();
+        core2.poll();
+        assert_eq!(received.borrow().len(), 1);
+
+        let mut msg = Message::id(3, rand_command(), &vec![0; MAX_TRANSFER_SIZE + 1]);
+        assert_eq!(
+            core1.send_large(m1, &mut msg),
+            Err(SendError::TooLarge(MAX_TRANSFER_SIZE + 1))
+        );
+    }
+    #[test]
+    fn expired_transfer() {
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let reported = errors.clone();
+        let now = Rc::new(Cell::new(0));
+
+        let mut core = Core::new();
+        probe(&mut core);
+        core.set_tick_source(Box::new(ManualClock(now.clone())));
+        core.set_error_callback(move |error: BusError| reported.borrow_mut().push(error));
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 3);
+
+        let msg = Message::id(3, rand_command(), &vec![0; 600]);
+        let fragments = transfer::split(&msg, 1);
+        inject(&mut core, 2, fragments[0].clone());
+
+        // Expires on a quiet bus.
+        now.set(transfer::TRANSFER_TIMEOUT);
+        core.poll();
+        assert!(errors.borrow().is_empty());
+        now.set(transfer::TRANSFER_TIMEOUT + 1);
+        core.poll();
+        assert_eq!(
+            *errors.borrow(),
+            vec![BusError::Transfer(TransferError::Incomplete(2))]
+        );
+    }
+    #[test]
+    fn local_benchmark() {
+        let dispatched = Rc::new(Cell::new(0));
+        let m2_dispatched = dispatched.clone();
+
+        let mut core = Core::new();
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 1);
+        let m2 = core.create_module("m2", rand_type(), move |_| {
+            m2_dispatched.set(m2_dispatched.get() + 1)
+        });
+        core.set_module_id(m2, 2);
+
+        let report = core.benchmark(m1, 2, 20, 8, 10).unwrap();
+        assert_eq!(report.sent, 20);
+        assert_eq!(report.received, 20);
+        assert_eq!(report.lost, 0);
+        assert_eq!(report.loss(), 0.0);
+        // Handled by the Core only.
+        assert_eq!(dispatched.get(), 0);
+
+        assert_eq!(
+            core.benchmark(m1, 42, 1, 8, 1),
+            Err(RequestError::Timeout(42))
+        );
+    }
+    #[test]
+    fn benchmark_reception_times() {
+        let now = Rc::new(Cell::new(0));
+        let mut core = Core::new();
+        let mut gate = probe(&mut core);
+        core.set_tick_source(Box::new(ManualClock(now.clone())));
+        let m2 = core.create_module("m2", rand_type(), |_| {});
+        core.set_module_id(m2, 2);
+
+        inject(&mut core, 1, Message::id(2, Command::DataRate, &vec![3, 0, 8]));
+        for &time in [1_000, 2_000, 4_000].iter() {
+            now.set(time);
+            inject(&mut core, 1, Message::id(2, Command::DataResult, &vec![0; 8]));
+        }
+        now.set(9_000);
+        inject(&mut core, 1, Message::id(2, Command::DataRate, &vec![]));
+
+        let answers = read_frames(&mut gate);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].header.command, Command::DataRateResult);
+        // 3 received, none lost, 3ms between the first and the last
+        assert_eq!(answers[0].data, vec![3, 0, 0, 0, 0xB8, 0x0B, 0, 0]);
+    }
+    #[test]
+    fn benchmark_over_bus() {
+        let bus = SimBus::new();
+        let target = Board::run(&bus, |core| {
+            let m3 = core.create_module("m3", rand_type(), |_| {});
+            core.set_module_id(m3, 3);
+        });
+
+        let mut gate = connected(&bus);
+        let m1 = gate.create_module("m1", rand_type(), |_| {});
+        gate.set_module_id(m1, 1);
+
+        let report = gate.benchmark(m1, 3, 50, 16, 1000);
+        target.stop();
+
+        let report = report.unwrap();
+        assert_eq!(report.received, 50);
+        assert_eq!(report.lost, 0);
+        assert_eq!(report.frame_size, HEADER_SIZE + 16 + 2);
+    }
+    #[test]
+    fn baudrate_switch() {
+        let now = Rc::new(Cell::new(0));
+        let bus = SimBus::new();
+
+        let mut gate = clocked(&bus, &now);
+        let m1 = gate.create_module("m1", rand_type(), |_| {});
+        gate.set_module_id(m1, 1);
+        let mut node = clocked(&bus, &now);
+        let mut late = clocked(&bus, &now);
+
+        gate.switch_baudrate(m1, 115_200, 5).unwrap();
+        node.poll();
+        late.poll();
+
+        // The late board misses the switch and its confirmation.
+        for _ in 0..50 {
+            now.set(now.get() + 1_000);
+            gate.poll();
+            node.poll();
+        }
+        assert_eq!(gate.baudrate(), 115_200);
+        assert_eq!(node.baudrate(), 115_200);
+
+        for _ in 0..300 {
+            now.set(now.get() + 1_000);
+            node.poll();
+            late.poll();
+        }
+        assert_eq!(node.baudrate(), 115_200);
+        assert_eq!(late.baudrate(), SIM_BAUDRATE);
+    }
+    #[test]
+    fn unsupported_baudrate() {
+        let now = Rc::new(Cell::new(0));
+        let mut core = Core::new();
+        probe(&mut core);
+        core.set_tick_source(Box::new(ManualClock(now.clone())));
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 1);
+
+        assert_eq!(
+            core.switch_baudrate(m1, 0, 5),
+            Err(SendError::UnsupportedBaudrate(0))
+        );
+        // Announced by another board
+        for baudrate in vec![0, 1, 1_000] {
+            let announce = baudrate::announce(baudrate, 5);
+            inject(&mut core, 2, Message::broadcast(Command::SetBaudrate, &announce));
+        }
+        // Neither applied nor confirmed
+        for _ in 0..300 {
+            now.set(now.get() + 1_000);
+            core.poll();
+            assert_eq!(core.baudrate(), SIM_BAUDRATE);
+        }
+    }
+    #[test]
+    fn heartbeats() {
+        let changes: Rc<RefCell<Vec<(u16, Presence)>>> = Rc::new(RefCell::new(Vec::new()));
+        let presence_changes = changes.clone();
+        let lost: Rc<RefCell<Vec<u16>>> = Rc::new(RefCell::new(Vec::new()));
+        let lost_ids = lost.clone();
+        let now = Rc::new(Cell::new(0));
+
+        let bus = SimBus::new();
+
+        let mut node = clocked(&bus, &now);
+        let m2 = node.create_module("m2", rand_type(), |_| {});
+        node.set_module_id(m2, 2);
+        let m3 = node.create_module("m3", rand_type(), |_| {});
+        node.set_module_id(m3, 3);
+        node.set_heartbeat(m2, 10);
+
+        let mut supervisor = clocked(&bus, &now);
+        supervisor.track_presence(30, 60, move |id, presence| {
+            presence_changes.borrow_mut().push((id, presence))
+        });
+        supervisor.set_module_lost_callback(move |id| lost_ids.borrow_mut().push(id));
+
+        for _ in 0..50 {
+            now.set(now.get() + 1_000);
+            node.poll();
+            supervisor.poll();
+        }
+        assert_eq!(
+            *changes.borrow(),
+            vec![(2, Presence::Alive), (3, Presence::Alive)]
+        );
+        assert_eq!(supervisor.presence(3), Some(Presence::Alive));
+        assert_eq!(supervisor.presence(4), None);
+
+        // The node stops talking.
+        node.stop_heartbeat();
+        for _ in 0..100 {
+            now.set(now.get() + 1_000);
+            node.poll();
+            supervisor.poll();
+        }
+        assert_eq!(
+            changes.borrow()[2..].to_vec(),
+            vec![
+                (2, Presence::Suspect),
+                (3, Presence::Suspect),
+                (2, Presence::Dead),
+                (3, Presence::Dead),
+            ]
+        );
+        assert_eq!(*lost.borrow(), vec![2, 3]);
+        assert_eq!(supervisor.presence(2), Some(Presence::Dead));
+    }
+    #[test]
+    fn time_sync() {
+        const SHIFT: u64 = 3_600_000_000;
+
+        let bus = SimBus::new();
+        let master = Board::run(&bus, |core| {
+            // Frozen clock: the round-trip takes no time.
+            core.set_tick_source(Box::new(ManualClock(Rc::new(Cell::new(SHIFT)))));
+            let m1 = core.create_module("m1", rand_type(), |_| {});
+            core.set_module_id(m1, 1);
+            core.set_time_master(m1, 5);
+        });
+
+        let mut node = connected(&bus);
+        let m2 = node.create_module("m2", rand_type(), |_| {});
+        node.set_module_id(m2, 2);
+
+        // No master behind module 3 (the board clock runs out the timeout)
+        assert_eq!(node.sync_time(m2, 3, 10), Err(RequestError::Timeout(3)));
+
+        let now = Rc::new(Cell::new(0));
+        node.set_tick_source(Box::new(ManualClock(now.clone())));
+        assert_eq!(node.sync_time(m2, 1, 1000), Ok(SHIFT as i64));
+        assert_eq!(node.bus_time(), SHIFT);
+
+        master.stop();
+
+        // Followed through the broadcasts of a new master, 2ms further ahead
+        let master_now = Rc::new(Cell::new(SHIFT + 2_000));
+        let mut master = clocked(&bus, &master_now);
+        let m1 = master.create_module("m1", rand_type(), |_| {});
+        master.set_module_id(m1, 1);
+        master.set_time_master(m1, 5);
+        for _ in 0..30 {
+            now.set(now.get() + 1_000);
+            master_now.set(master_now.get() + 1_000);
+            master.poll();
+            node.poll();
+        }
+        assert_eq!(node.bus_time(), master_now.get());
+    }
+    #[test]
+    fn time_broadcasts_from_the_master_only() {
+        let now = Rc::new(Cell::new(10_000));
+        let mut core = Core::new();
+        let _bus = probe(&mut core);
+        core.set_tick_source(Box::new(ManualClock(now.clone())));
+        let m1 = core.create_module("m1", rand_type(), |_| {});
+        core.set_module_id(m1, 2);
+
+        let broadcast =
+            |time: u64| Message::broadcast(Command::TimeSync, &clock::encode(time).to_vec());
+
+        // Not synchronized yet
+        inject(&mut core, 1, broadcast(50_000));
+        assert_eq!(core.bus_time(), 10_000);
+
+        // As after a `sync_time` with module 1
+        let answer = clock::answer(10_000, 10_000);
+        core.time.sync(1, 10_000, &answer, 10_000).unwrap();
+        inject(&mut core, 3, broadcast(90_000));
+        assert_eq!(core.bus_time(), 10_000);
+        inject(&mut core, 1, broadcast(50_000));
+        assert_eq!(core.bus_time(), 50_000);
+
+        // A master keeps its own time.
+        core.set_time_master(m1, 5);
+        inject(&mut core, 1, broadcast(90_000));
+        inject(&mut core, 3, broadcast(90_000));
+        assert_eq!(core.bus_time(), 10_000);
+    }
+    /// Module answering `Command::Identify` with the given data.
+    fn introduced<'a>(
+        core: &mut Core<'a>,
+        id: u16,
+        alias: &str,
+        mod_type: ModuleType,
+        data: Vec<u8>,
+    ) {
+        let m = core.create_module_with_reply(alias, mod_type, move |msg| {
+            match msg.header.command {
+                Command::Identify => Some(Message::reply(Command::Introduction, &data)),
+                _ => None,
+            }
+        });
+        core.set_module_id(m, id);
+    }
+    #[test]
+    fn discovery() {
+        let bus = SimBus::new();
+        let board = Board::run(&bus, |core| {
+            let servo = introduction("servo_1", ModuleType::Servo);
+            introduced(core, 2, "servo_1", ModuleType::Servo, servo);
+            let servo = introduction("servo_2", ModuleType::Servo);
+            introduced(core, 3, "servo_2", ModuleType::Servo, servo);
+            introduced(core, 4, "broken", ModuleType::Relay, vec![]);
+            // Silent module
+            let m5 = core.create_module("m5", ModuleType::Relay, |_| {});
+            core.set_module_id(m5, 5);
+        });
+
+        let mut gate = connected(&bus);
+        let m1 = gate.create_module("gate", ModuleType::Gate, |_| {});
+        gate.set_module_id(m1, 1);
+        let button = introduction("button", ModuleType::Button);
+        introduced(&mut gate, 6, "button", ModuleType::Button, button);
+
+        let directory = gate.discover(m1, 100).unwrap();
+        let ids: Vec<u16> = directory.iter().map(|module| module.id).collect();
+        assert_eq!(ids, vec![2, 3, 6]);
+        assert_eq!(directory.get(2).unwrap().alias, "servo_1");
+        assert_eq!(directory.find("servo_2").unwrap().id, 3);
+        assert_eq!(directory.find("button").unwrap().mod_type, ModuleType::Button);
+        assert_eq!(directory.of_type(ModuleType::Servo).len(), 2);
+        assert_eq!(directory.get(4), None);
+
+        // Only the request reached our button, the answers did not reach the gate module.
+        assert_eq!(gate.stats().dispatched, 1);
+
+        board.stop();
+    }
+    /// `TickSource` driven by the test (in µs).
+    struct ManualClock(Rc<Cell<u64>>);
+    impl TickSource for ManualClock {
+        fn micros(&mut self) -> u64 {
+            self.0.get()
+        }
+    }
+    /// Core connected to the simulated bus.
+    fn connected<'a>(bus: &SimBus) -> Core<'a> {
+        let mut core = Core::new();
+        core.set_port(Box::new(bus.connect()));
+        core
+    }
+    /// Core connected to the simulated bus and timed by `now` (in µs).
+    fn clocked<'a>(bus: &SimBus, now: &Rc<Cell<u64>>) -> Core<'a> {
+        let mut core = connected(bus);
+        core.set_tick_source(Box::new(ManualClock(now.clone())));
+        core
+    }
+    /// Board polling its own Core in a thread until stopped.
+    struct Board {
+        running: Arc<AtomicBool>,
+        thread: thread::JoinHandle<()>,
+    }
+    impl Board {
+        /// Runs a Core connected to the simulated bus and set up by `setup`.
+        fn run<F>(bus: &SimBus, setup: F) -> Board
+        where
+            F: FnOnce(&mut Core) + Send + 'static,
+        {
+            let port = bus.connect();
+            let running = Arc::new(AtomicBool::new(true));
+            let board_running = running.clone();
+            let thread = thread::spawn(move || {
+                let mut core = Core::new();
+                core.set_port(Box::new(port));
+                setup(&mut core);
+                while board_running.load(Ordering::SeqCst) {
+                    core.poll();
+                    thread::sleep(time::Duration::from_millis(1));
+                }
+            });
+            Board { running, thread }
+        }
+        fn stop(self) {
+            self.running.store(false, Ordering::SeqCst);
+            self.thread.join().unwrap();
+        }
+    }
+    fn inject(core: &mut Core, source: u16, mut msg: Message) {
+        msg.header.source = source;
+        for byte in msg.to_bytes() {
+            core.receive(byte);
+        }
+    }
+    /// Connects the core to a simulated bus and returns a port observing it.
+    fn probe(core: &mut Core) -> SimPort {
+        let bus = SimBus::new();
+        core.set_port(Box::new(bus.connect()));
+        bus.connect()
+    }
+    /// Reads the frames written on the bus since the last call.
+    fn read_frames(port: &mut SimPort) -> Vec<Message> {
+        let mut recv_buf = RecvBuf::new();
+        let mut frames = Vec::new();
+        while let Some(event) = port.poll() {
+            match event {
+                RxEvent::Byte(byte) => {
+                    recv_buf.push(byte);
+                    if let Some(Ok(msg)) = recv_buf.get_message() {
+                        frames.push(msg);
+                    }
+                }
+                RxEvent::Timeout => recv_buf.flush(),
+            }
+        }
+        frames
+    }
+    fn sim_lines(lines: Vec<SimLine>) -> Vec<Box<PtpLine>> {
+        lines
+            .into_iter()
+            .map(|line| Box::new(line) as Box<PtpLine>)
+            .collect()
+    }
     fn rand_id_msg() -> Message {
         Message::id(rand_id(), rand_command(), &rand_data(rand_data_size()))
     }
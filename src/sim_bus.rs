@@ -0,0 +1,323 @@
+//! Simulated bus
+//!
+//! Allows to connect several `Core` on the host without any board. Each core may run in its own thread as the ports can be sent across threads.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+use collections::Priority;
+use physical::{self, Port, RxEvent, QUEUED_TX_ATTEMPTS, TX_QUEUE_SIZE};
+
+/// Initial baudrate of the simulated ports.
+pub const SIM_BAUDRATE: u32 = 1_000_000;
+
+/// Simulated bus: the frames sent by a port are received by all the other ones using the same baudrate.
+///
+/// As on the real bus, each frame is followed by a timeout (`RxEvent::Timeout`). The ports start at `SIM_BAUDRATE`.
+#[derive(Clone)]
+pub struct SimBus {
+    wire: Arc<Mutex<Wire>>,
+}
+
+struct Wire {
+    /// Reception events waiting to be read by each port.
+    queues: Vec<VecDeque<RxEvent>>,
+    /// Baudrate of each port.
+    baudrates: Vec<u32>,
+    /// Number of the next frames colliding with another transmitter.
+    collisions: usize,
+}
+
+impl SimBus {
+    pub fn new() -> SimBus {
+        SimBus {
+            wire: Arc::new(Mutex::new(Wire {
+                queues: Vec::new(),
+                baudrates: Vec::new(),
+                collisions: 0,
+            })),
+        }
+    }
+    /// Connects a new port to the bus.
+    ///
+    /// The port only receives the frames sent after its connection.
+    pub fn connect(&self) -> SimPort {
+        let mut wire = self.wire.lock().unwrap();
+        wire.queues.push(VecDeque::new());
+        wire.baudrates.push(SIM_BAUDRATE);
+
+        SimPort {
+            bus: self.clone(),
+            node: wire.queues.len() - 1,
+            pending: VecDeque::new(),
+            attempts: 0,
+            backoff: 0,
+            seed: physical::unique_seed() ^ wire.queues.len() as u32,
+        }
+    }
+    /// Simulate another transmitter talking at the same time as the next `frames` frames.
+    ///
+    /// The senders detect the collisions on the first byte and abort their frame: the other ports only get a corrupted byte.
+    pub fn collide(&self, frames: usize) {
+        self.wire.lock().unwrap().collisions += frames;
+    }
+    /// Write a frame for all the ports but `from`.
+    ///
+    /// The ports using another baudrate get nothing. Returns `false` if the frame collided.
+    fn write(&self, from: usize, bytes: &[u8]) -> bool {
+        // The whole frame is written at once so frames never interleave.
+        let mut wire = self.wire.lock().unwrap();
+        let collided = wire.collisions > 0;
+        let written = if collided {
+            wire.collisions -= 1;
+            vec![!bytes[0]]
+        } else {
+            bytes.to_vec()
+        };
+
+        // Borrow the fields separately.
+        let wire = &mut *wire;
+        let baudrate = wire.baudrates[from];
+        for (node, queue) in wire.queues.iter_mut().enumerate() {
+            if node != from && wire.baudrates[node] == baudrate {
+                queue.extend(written.iter().map(|byte| RxEvent::Byte(*byte)));
+                queue.push_back(RxEvent::Timeout);
+            }
+        }
+        !collided
+    }
+}
+
+/// Port connected to a `SimBus` (see `Core::set_port`).
+///
+/// The queued frames (see `Port::enqueue`) are sent by order of priority at the next `poll`, as the interruptions of a board would do in the background. A collided frame waits a random number of polls before being sent again, it keeps its place in the queue meanwhile.
+pub struct SimPort {
+    bus: SimBus,
+    node: usize,
+    /// Frames waiting in the transmit queue, with their priority.
+    pending: VecDeque<(Vec<u8>, Priority)>,
+    /// Attempts to send the first queued frame.
+    attempts: u8,
+    /// Polls left to wait before sending the first queued frame again.
+    backoff: u32,
+    seed: u32,
+}
+
+impl SimPort {
+    /// Send the queued frames.
+    fn flush(&mut self) {
+        if self.backoff > 0 {
+            self.backoff -= 1;
+            return;
+        }
+        while let Some((frame, priority)) = self.pending.pop_front() {
+            if !self.bus.write(self.node, &frame) {
+                self.attempts += 1;
+                if self.attempts < QUEUED_TX_ATTEMPTS {
+                    // Sent again after a random number of polls.
+                    self.backoff = physical::backoff(&mut self.seed, self.attempts as u16);
+                    self.pending.push_front((frame, priority));
+                    return;
+                }
+            }
+            self.attempts = 0;
+        }
+    }
+    /// Bytes of the queued frames of the given priorities.
+    fn queued<F: Fn(Priority) -> bool>(&self, filter: F) -> usize {
+        self.pending
+            .iter()
+            .filter(|&&(_, priority)| filter(priority))
+            .map(|&(ref frame, _)| frame.len())
+            .sum()
+    }
+}
+
+impl Port for SimPort {
+    fn lock(&mut self) {}
+    fn send(&mut self, bytes: &[u8]) -> bool {
+        // The queued frames go first, unless they are waiting for their backoff.
+        self.flush();
+        self.bus.write(self.node, bytes)
+    }
+    fn enqueue(&mut self, bytes: &[u8], priority: Priority) -> bool {
+        if bytes.len() > TX_QUEUE_SIZE - self.queued(|_| true) {
+            return false;
+        }
+        if priority == Priority::Bulk
+            && bytes.len() > TX_QUEUE_SIZE / 2 - self.queued(|p| p == Priority::Bulk)
+        {
+            return false;
+        }
+        // A collided frame keeps its place until it goes through.
+        let first = if self.attempts > 0 { 1 } else { 0 };
+        let pos = (first..self.pending.len())
+            .find(|&i| self.pending[i].1 > priority)
+            .unwrap_or(self.pending.len());
+        self.pending.insert(pos, (bytes.to_vec(), priority));
+        true
+    }
+    fn tx_empty(&mut self) -> bool {
+        self.pending.is_empty()
+    }
+    fn poll(&mut self) -> Option<RxEvent> {
+        self.flush();
+        let mut wire = self.bus.wire.lock().unwrap();
+        wire.queues[self.node].pop_front()
+    }
+    fn baudrate(&self) -> u32 {
+        self.bus.wire.lock().unwrap().baudrates[self.node]
+    }
+    fn set_baudrate(&mut self, baudrate: u32) {
+        self.bus.wire.lock().unwrap().baudrates[self.node] = baudrate;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use msg::tests::rand_msg;
+
+    #[test]
+    fn other_ports_receive() {
+        let bus = SimBus::new();
+        let mut a = bus.connect();
+        let mut b = bus.connect();
+        let mut c = bus.connect();
+
+        let bytes = rand_msg().to_bytes();
+        assert!(a.send(&bytes));
+
+        assert_eq!(a.poll(), None);
+        for port in [&mut b, &mut c].iter_mut() {
+            let mut received = Vec::new();
+            while let Some(RxEvent::Byte(byte)) = port.poll() {
+                received.push(byte);
+            }
+            assert_eq!(received, bytes);
+            assert_eq!(port.poll(), None);
+        }
+
+        // Late ports miss the previous frames.
+        let mut d = bus.connect();
+        assert_eq!(d.poll(), None);
+    }
+    #[test]
+    fn bounded_queue() {
+        let bus = SimBus::new();
+        let mut a = bus.connect();
+        let mut b = bus.connect();
+
+        let frame = vec![0; TX_QUEUE_SIZE / 2];
+        assert!(a.enqueue(&frame, Priority::Bulk));
+        assert!(!a.enqueue(&[0], Priority::Bulk));
+        assert!(a.enqueue(&frame, Priority::Normal));
+        assert!(!a.enqueue(&[0], Priority::Emergency));
+        assert_eq!(b.poll(), None);
+
+        // Sent in the background.
+        a.poll();
+        let mut received = 0;
+        while let Some(event) = b.poll() {
+            if let RxEvent::Byte(_) = event {
+                received += 1;
+            }
+        }
+        assert_eq!(received, TX_QUEUE_SIZE);
+        assert!(a.enqueue(&[0], Priority::Normal));
+    }
+    #[test]
+    fn collisions() {
+        let bus = SimBus::new();
+        let mut a = bus.connect();
+        let mut b = bus.connect();
+
+        let bytes = rand_msg().to_bytes();
+        bus.collide(1);
+        assert!(!a.send(&bytes));
+        assert_eq!(b.poll(), Some(RxEvent::Byte(!bytes[0])));
+        assert_eq!(b.poll(), Some(RxEvent::Timeout));
+        assert!(a.send(&bytes));
+
+        // Queued frames are sent again after a random number of polls.
+        bus.collide(1);
+        assert!(a.enqueue(&bytes, Priority::Normal));
+        a.poll();
+        a.poll();
+        assert!(!a.tx_empty());
+        let mut polls = 0;
+        while !a.tx_empty() {
+            a.poll();
+            polls += 1;
+        }
+        assert!(polls <= 2);
+
+        // Only the collided frame is dropped once out of attempts.
+        bus.collide(QUEUED_TX_ATTEMPTS as usize);
+        assert!(a.enqueue(&bytes, Priority::Normal));
+        assert!(a.enqueue(&[42], Priority::Normal));
+        while !a.tx_empty() {
+            a.poll();
+        }
+        let mut received = Vec::new();
+        while let Some(event) = b.poll() {
+            received.push(event);
+        }
+        assert_eq!(received[received.len() - 2..], [RxEvent::Byte(42), RxEvent::Timeout]);
+    }
+    #[test]
+    fn priority_order() {
+        let bus = SimBus::new();
+        let mut a = bus.connect();
+        let mut b = bus.connect();
+
+        // The collided frame keeps its place, the others are sorted.
+        bus.collide(1);
+        assert!(a.enqueue(&[3], Priority::Bulk));
+        a.poll();
+        assert!(a.enqueue(&[4], Priority::Bulk));
+        assert!(a.enqueue(&[2], Priority::Normal));
+        assert!(a.enqueue(&[1], Priority::Emergency));
+        while !a.tx_empty() {
+            a.poll();
+        }
+        let mut bytes = Vec::new();
+        while let Some(event) = b.poll() {
+            if let RxEvent::Byte(byte) = event {
+                bytes.push(byte);
+            }
+        }
+        assert_eq!(bytes, vec![!3, 3, 1, 2, 4]);
+    }
+    #[test]
+    fn desynchronized_backoffs() {
+        let bus = SimBus::new();
+        let mut a = bus.connect();
+        let mut b = bus.connect();
+
+        // The ports draw their own delays.
+        let delays = |port: &mut SimPort| {
+            (1..7)
+                .map(|attempt| physical::backoff(&mut port.seed, attempt))
+                .collect::<Vec<u32>>()
+        };
+        assert_ne!(delays(&mut a), delays(&mut b));
+    }
+    #[test]
+    fn baudrates() {
+        let bus = SimBus::new();
+        let mut a = bus.connect();
+        let mut b = bus.connect();
+        assert_eq!(a.baudrate(), SIM_BAUDRATE);
+
+        a.set_baudrate(115_200);
+        assert!(a.send(&rand_msg().to_bytes()));
+        assert_eq!(b.poll(), None);
+
+        b.set_baudrate(115_200);
+        assert!(a.send(&rand_msg().to_bytes()));
+        assert!(b.poll().is_some());
+    }
+}
@@ -0,0 +1,171 @@
+//! Bus statistics
+//!
+//! The `Core` counts the bytes and frames going through the bus and the rejected ones (see `Core::stats`). The counters wrap around on overflow.
+
+use msg::{ParsingError, TargetMode};
+
+/// Number of frames for each `TargetMode`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameCounters {
+    pub id: u32,
+    pub id_ack: u32,
+    pub type_msg: u32,
+    pub broadcast: u32,
+    pub multicast: u32,
+}
+
+impl FrameCounters {
+    /// Counts a frame sent with the given `TargetMode`.
+    pub fn count(&mut self, mode: TargetMode) {
+        let counter = match mode {
+            TargetMode::Id => &mut self.id,
+            TargetMode::IdAck => &mut self.id_ack,
+            TargetMode::Type => &mut self.type_msg,
+            TargetMode::Broadcast => &mut self.broadcast,
+            TargetMode::Multicast => &mut self.multicast,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+    /// Number of frames, all target modes included.
+    pub fn total(&self) -> u32 {
+        self.id
+            .wrapping_add(self.id_ack)
+            .wrapping_add(self.type_msg)
+            .wrapping_add(self.broadcast)
+            .wrapping_add(self.multicast)
+    }
+}
+
+/// Snapshot of the bus activity since the creation of the `Core` (or the last `Core::reset_stats`).
+///
+/// The byte, frame, error and collision counters only cover the bus: the messages exchanged between the modules of the same `Core` never reach it. `dispatched` counts every message given to our modules, local ones included.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Bytes received from the bus.
+    pub bytes_rx: u32,
+    /// Bytes written on the bus.
+    pub bytes_tx: u32,
+    /// Valid frames received from the bus.
+    pub frames_rx: FrameCounters,
+    /// Frames written on the bus.
+    pub frames_tx: FrameCounters,
+    /// Messages given to the modules callbacks, from the bus or from our own modules (sniffers excluded).
+    pub dispatched: u32,
+    /// Frames rejected because of their CRC.
+    pub crc_errors: u32,
+    /// Frames rejected because of an unknown command.
+    pub command_errors: u32,
+    /// Frames rejected because of their data size.
+    pub data_size_errors: u32,
+    /// Frames rejected because of their size.
+    pub header_size_errors: u32,
+    /// Frames rejected because of an unknown protocol revision.
+    pub protocol_errors: u32,
+    /// Frames rejected because of an unknown target mode.
+    pub target_mode_errors: u32,
+    /// Frames interrupted by the bus timeout before their end.
+    pub timeouts: u32,
+    /// Frames aborted because another board was talking at the same time (they are sent again).
+    pub collisions: u32,
+}
+
+impl Stats {
+    /// Counts a valid frame received from the bus.
+    pub fn count_rx(&mut self, mode: TargetMode) {
+        self.frames_rx.count(mode);
+    }
+    /// Counts a frame of `len` bytes written on the bus.
+    pub fn count_tx(&mut self, mode: TargetMode, len: usize) {
+        self.bytes_tx = self.bytes_tx.wrapping_add(len as u32);
+        self.frames_tx.count(mode);
+    }
+    /// Counts a byte received from the bus.
+    pub fn count_byte(&mut self) {
+        self.bytes_rx = self.bytes_rx.wrapping_add(1);
+    }
+    /// Counts a message given to a module callback.
+    pub fn count_dispatch(&mut self) {
+        self.dispatched = self.dispatched.wrapping_add(1);
+    }
+    /// Counts a frame interrupted by the bus timeout.
+    pub fn count_timeout(&mut self) {
+        self.timeouts = self.timeouts.wrapping_add(1);
+    }
+    /// Counts a frame aborted by a collision.
+    pub fn count_collision(&mut self) {
+        self.collisions = self.collisions.wrapping_add(1);
+    }
+    /// Counts a frame rejected by the reception.
+    pub fn count_error(&mut self, error: &ParsingError) {
+        let counter = match *error {
+            ParsingError::InvalidCommand(_) => &mut self.command_errors,
+            ParsingError::InvalidCrc(_) => &mut self.crc_errors,
+            ParsingError::InvalidDataSize(_) => &mut self.data_size_errors,
+            ParsingError::InvalidHeaderSize(_) => &mut self.header_size_errors,
+            ParsingError::InvalidProtocol(_) => &mut self.protocol_errors,
+            ParsingError::InvalidTargetMode(_) => &mut self.target_mode_errors,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+    /// Number of rejected and interrupted frames.
+    pub fn errors(&self) -> u32 {
+        self.crc_errors
+            .wrapping_add(self.command_errors)
+            .wrapping_add(self.data_size_errors)
+            .wrapping_add(self.header_size_errors)
+            .wrapping_add(self.protocol_errors)
+            .wrapping_add(self.target_mode_errors)
+            .wrapping_add(self.timeouts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_frames() {
+        let mut counters = FrameCounters::default();
+        counters.count(TargetMode::Id);
+        counters.count(TargetMode::Broadcast);
+        counters.count(TargetMode::Broadcast);
+
+        assert_eq!(counters.id, 1);
+        assert_eq!(counters.broadcast, 2);
+        assert_eq!(counters.total(), 3);
+    }
+    #[test]
+    fn count_errors() {
+        let mut stats = Stats::default();
+        stats.count_error(&ParsingError::InvalidCrc((1, 2)));
+        stats.count_error(&ParsingError::InvalidProtocol(0x0F));
+        stats.timeouts = 1;
+
+        assert_eq!(stats.crc_errors, 1);
+        assert_eq!(stats.protocol_errors, 1);
+        assert_eq!(stats.errors(), 3);
+
+        stats.crc_errors = u32::max_value();
+        stats.count_error(&ParsingError::InvalidCrc((1, 2)));
+        assert_eq!(stats.crc_errors, 0);
+    }
+    #[test]
+    fn count_traffic() {
+        let mut stats = Stats::default();
+        stats.count_byte();
+        stats.count_rx(TargetMode::Id);
+        stats.count_tx(TargetMode::Broadcast, 12);
+        stats.count_tx(TargetMode::Broadcast, 8);
+        stats.count_dispatch();
+        stats.count_collision();
+        stats.count_timeout();
+
+        assert_eq!((stats.bytes_rx, stats.frames_rx.id), (1, 1));
+        assert_eq!((stats.bytes_tx, stats.frames_tx.broadcast), (20, 2));
+        assert_eq!((stats.dispatched, stats.collisions, stats.timeouts), (1, 1, 1));
+
+        stats.bytes_tx = u32::max_value();
+        stats.count_tx(TargetMode::Id, 2);
+        assert_eq!(stats.bytes_tx, 1);
+    }
+}
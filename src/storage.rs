@@ -0,0 +1,173 @@
+//! Persistent storage of the modules aliases.
+//!
+//! Aliases written through the bus (`Command::WriteAlias`) are kept in an `AliasStorage` so they survive reboots.
+
+use alloc::String;
+use alloc::vec::Vec;
+
+/// Storage of the modules aliases
+///
+/// Aliases are identified by the local index of the module (its creation order on the board).
+pub trait AliasStorage {
+    /// Returns the alias stored for the module `index`.
+    fn load(&self, index: usize) -> Option<String>;
+    /// Stores the alias of the module `index`.
+    fn store(&mut self, index: usize, alias: &str);
+    /// Forgets the alias of the module `index` (its index may be given to a new module).
+    fn remove(&mut self, index: usize);
+}
+
+/// Volatile `AliasStorage` - aliases are lost at reboot.
+pub struct MemoryStorage {
+    aliases: Vec<Option<String>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage {
+            aliases: Vec::new(),
+        }
+    }
+}
+
+impl AliasStorage for MemoryStorage {
+    fn load(&self, index: usize) -> Option<String> {
+        match self.aliases.get(index) {
+            Some(&Some(ref alias)) => Some(alias.clone()),
+            _ => None,
+        }
+    }
+    fn store(&mut self, index: usize, alias: &str) {
+        while self.aliases.len() <= index {
+            self.aliases.push(None);
+        }
+        self.aliases[index] = Some(String::from(alias));
+    }
+    fn remove(&mut self, index: usize) {
+        if let Some(alias) = self.aliases.get_mut(index) {
+            *alias = None;
+        }
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+mod file {
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    use super::AliasStorage;
+
+    /// `AliasStorage` backed by a file on the host - one alias per line.
+    pub struct FileStorage {
+        path: String,
+    }
+
+    impl FileStorage {
+        /// Creates a storage using the file `path` (created on the first store).
+        pub fn new(path: &str) -> FileStorage {
+            FileStorage {
+                path: path.to_string(),
+            }
+        }
+        fn read(&self) -> Vec<String> {
+            let mut content = String::new();
+            if let Ok(mut file) = File::open(&self.path) {
+                if file.read_to_string(&mut content).is_err() {
+                    return Vec::new();
+                }
+            }
+            content.lines().map(|line| line.to_string()).collect()
+        }
+        fn write(&self, aliases: &[String]) {
+            if let Ok(mut file) = File::create(&self.path) {
+                for alias in aliases.iter() {
+                    let _ = file.write_all(alias.as_bytes());
+                    let _ = file.write_all(b"\n");
+                }
+            }
+        }
+    }
+
+    impl AliasStorage for FileStorage {
+        fn load(&self, index: usize) -> Option<String> {
+            match self.read().get(index) {
+                Some(alias) if !alias.is_empty() => Some(alias.clone()),
+                _ => None,
+            }
+        }
+        fn store(&mut self, index: usize, alias: &str) {
+            let mut aliases = self.read();
+            while aliases.len() <= index {
+                aliases.push(String::new());
+            }
+            aliases[index] = alias.to_string();
+            self.write(&aliases);
+        }
+        fn remove(&mut self, index: usize) {
+            let mut aliases = self.read();
+            match aliases.get_mut(index) {
+                Some(alias) if !alias.is_empty() => alias.clear(),
+                _ => return,
+            }
+            self.write(&aliases);
+        }
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub use self::file::FileStorage;
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    use self::std::env;
+    use self::std::fs;
+
+    #[test]
+    fn memory_storage() {
+        let mut storage = MemoryStorage::new();
+
+        assert_eq!(storage.load(0), None);
+
+        storage.store(2, "left_arm");
+        assert_eq!(storage.load(0), None);
+        assert_eq!(storage.load(2), Some(String::from("left_arm")));
+
+        storage.store(2, "right_arm");
+        assert_eq!(storage.load(2), Some(String::from("right_arm")));
+
+        storage.remove(2);
+        storage.remove(5);
+        assert_eq!(storage.load(2), None);
+    }
+    #[test]
+    fn file_storage() {
+        let path = env::temp_dir().join("robus_aliases_test");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut storage = FileStorage::new(path);
+        assert_eq!(storage.load(1), None);
+
+        storage.store(1, "gripper");
+        storage.store(0, "wrist");
+
+        // A new storage on the same file reads the aliases back (e.g. after a reboot).
+        let storage = FileStorage::new(path);
+        assert_eq!(storage.load(0), Some(String::from("wrist")));
+        assert_eq!(storage.load(1), Some(String::from("gripper")));
+        assert_eq!(storage.load(2), None);
+
+        let mut storage = storage;
+        storage.remove(0);
+        assert_eq!(storage.load(0), None);
+        assert_eq!(storage.load(1), Some(String::from("gripper")));
+
+        fs::remove_file(path).unwrap();
+    }
+}
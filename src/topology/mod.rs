@@ -0,0 +1,438 @@
+//! Topology detection - discovers the physical tree of the nodes using the point-to-point (PTP) lines.
+//!
+//! Starting from the root (typically the gate), each detected node pokes its branches one after the other. Each neighbour answering a poke is given the next free ids (one per module it hosts) and explores its own branches in turn (depth-first).
+
+mod ptp;
+pub use self::ptp::{Ptp, PtpLine};
+
+#[cfg(not(target_arch = "arm"))]
+pub mod sim;
+
+use alloc::String;
+use alloc::vec::Vec;
+
+use error;
+use msg::MAX_ID_VAL;
+
+/// A node of the topology tree.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Node {
+    /// The bus id of the node.
+    pub id: u16,
+    /// The id of the node it is connected to (`None` for the root).
+    pub parent: Option<u16>,
+    /// The branch of the parent the node is connected to (`None` for the root).
+    pub branch: Option<usize>,
+}
+
+/// Tree of the detected nodes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Topology {
+    nodes: Vec<Node>,
+}
+
+impl Topology {
+    /// Creates a topology only containing its root.
+    pub fn new(root: u16) -> Topology {
+        Topology {
+            nodes: vec![
+                Node {
+                    id: root,
+                    parent: None,
+                    branch: None,
+                },
+            ],
+        }
+    }
+    /// Returns the root node.
+    pub fn root(&self) -> &Node {
+        &self.nodes[0]
+    }
+    /// Returns all the nodes in their detection order.
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+    /// Number of nodes (root included).
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+    /// Returns the node with the given id.
+    pub fn get(&self, id: u16) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.id == id)
+    }
+    /// Returns the id of the parent of the node `id`.
+    pub fn parent(&self, id: u16) -> Option<u16> {
+        self.get(id).and_then(|node| node.parent)
+    }
+    /// Returns the ids of the nodes directly connected to the node `id`.
+    pub fn children(&self, id: u16) -> Vec<u16> {
+        self.nodes
+            .iter()
+            .filter(|node| node.parent == Some(id))
+            .map(|node| node.id)
+            .collect()
+    }
+    fn add(&mut self, node: Node) {
+        self.nodes.push(node);
+    }
+}
+
+/// Operations needed by the detection to drive the nodes.
+pub trait Network {
+    /// Asks the node `id` to poke its next unexplored branches.
+    ///
+    /// Returns the branch as soon as a neighbour answers a poke, `None` once all the branches have been explored.
+    fn poke_next(&mut self, id: u16) -> Option<usize>;
+    /// Asks the neighbour currently answering a poke how many ids it needs.
+    ///
+    /// Returns 0 if nobody answered.
+    fn request_ids(&mut self) -> u16;
+    /// Gives its first id `id` to the neighbour currently answering a poke.
+    ///
+    /// Returns `false` if nobody took it.
+    fn assign(&mut self, id: u16) -> bool;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DetectionError {
+    /// More nodes than available ids were detected.
+    IdOverflow,
+    /// The boards could not be told to reset their detection state.
+    ResetFailed,
+    /// A neighbour of the given node answered its poke but did not ask for any id.
+    ///
+    /// It still holds its PTP line: the detection needs to be reset.
+    NoIdRequested(u16),
+    /// The given id was not taken by the neighbour answering a poke.
+    ///
+    /// It still holds its PTP line: the detection needs to be reset.
+    AssignFailed(u16),
+}
+
+impl error::Error for DetectionError {
+    fn description(&self) -> String {
+        match *self {
+            DetectionError::IdOverflow => format!("No id left for the detected nodes"),
+            DetectionError::ResetFailed => format!("Detection reset could not be sent"),
+            DetectionError::NoIdRequested(id) => {
+                format!("A neighbour of node {} did not request any id", id)
+            }
+            DetectionError::AssignFailed(id) => format!("Id {} was not taken", id),
+        }
+    }
+}
+
+/// Detects the topology of the network.
+///
+/// The nodes are given consecutive ids in their detection order.
+///
+/// # Arguments
+///
+/// * `net` - The `Network` used to drive the nodes.
+/// * `root` - The u16 id of the root node (which must already be marked as root).
+/// * `first_id` - The first u16 id available for the detected nodes.
+pub fn detect_topology<N: Network>(
+    net: &mut N,
+    root: u16,
+    first_id: u16,
+) -> Result<Topology, DetectionError> {
+    let mut topology = Topology::new(root);
+    let mut next_id = first_id;
+    let mut path = vec![root];
+
+    loop {
+        let id = match path.last() {
+            Some(id) => *id,
+            None => break,
+        };
+
+        match net.poke_next(id) {
+            Some(branch) => {
+                let count = net.request_ids();
+                // Poking the next branch would make two boards answer at once.
+                if count == 0 {
+                    return Err(DetectionError::NoIdRequested(id));
+                }
+                // MAX_ID_VAL is reserved for broadcast.
+                if next_id as u32 + count as u32 > MAX_ID_VAL as u32 {
+                    return Err(DetectionError::IdOverflow);
+                }
+                if !net.assign(next_id) {
+                    return Err(DetectionError::AssignFailed(next_id));
+                }
+                topology.add(Node {
+                    id: next_id,
+                    parent: Some(id),
+                    branch: Some(branch),
+                });
+                path.push(next_id);
+                next_id += count;
+            }
+            None => {
+                path.pop();
+            }
+        }
+    }
+
+    Ok(topology)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::boxed::Box;
+
+    use super::sim::{ptp_wire, SimLine};
+    use super::ptp::tests::sim_ptp;
+
+    struct SimNode {
+        id: Option<u16>,
+        ids: u16,
+        /// Whether the node takes the id it is given.
+        accepts_id: bool,
+        ptp: Ptp,
+    }
+
+    /// Network where the nodes are directly driven through their simulated PTP lines.
+    struct SimNetwork {
+        nodes: Vec<SimNode>,
+    }
+
+    impl SimNetwork {
+        /// Builds a network of nodes with the given number of branches.
+        ///
+        /// The links are `(node, branch, node, branch)`, the first node being the root.
+        fn new(branches: &[usize], links: &[(usize, usize, usize, usize)]) -> SimNetwork {
+            let mut lines: Vec<Vec<Option<SimLine>>> = branches
+                .iter()
+                .map(|n| (0..*n).map(|_| None).collect())
+                .collect();
+
+            for &(n1, b1, n2, b2) in links {
+                let (a, b) = ptp_wire();
+                lines[n1][b1] = Some(a);
+                lines[n2][b2] = Some(b);
+            }
+
+            let mut nodes: Vec<SimNode> = lines
+                .into_iter()
+                .map(|lines| SimNode {
+                    id: None,
+                    ids: 1,
+                    accepts_id: true,
+                    ptp: sim_ptp(
+                        lines
+                            .into_iter()
+                            .map(|line| line.unwrap_or_else(SimLine::open))
+                            .collect(),
+                    ),
+                })
+                .collect();
+
+            nodes[0].id = Some(ROOT);
+            nodes[0].ptp.set_root();
+
+            SimNetwork { nodes }
+        }
+        fn id(&self, node: usize) -> Option<u16> {
+            self.nodes[node].id
+        }
+    }
+
+    impl Network for SimNetwork {
+        fn poke_next(&mut self, id: u16) -> Option<usize> {
+            let poker = self.nodes
+                .iter()
+                .position(|node| node.id == Some(id))
+                .unwrap();
+
+            while let Some(branch) = self.nodes[poker].ptp.next_branch() {
+                self.nodes[poker].ptp.begin_poke(branch);
+                for node in self.nodes.iter_mut() {
+                    node.ptp.poll();
+                }
+                if self.nodes[poker].ptp.end_poke(branch) {
+                    return Some(branch);
+                }
+            }
+            None
+        }
+        fn request_ids(&mut self) -> u16 {
+            match self.nodes.iter().find(|node| node.ptp.is_poked()) {
+                Some(node) => node.ids,
+                None => 0,
+            }
+        }
+        fn assign(&mut self, id: u16) -> bool {
+            for node in self.nodes.iter_mut() {
+                if node.ptp.is_poked() {
+                    if !node.accepts_id {
+                        return false;
+                    }
+                    node.ptp.acknowledge();
+                    node.id = Some(id);
+                    return true;
+                }
+            }
+            false
+        }
+    }
+
+    const ROOT: u16 = 1;
+
+    #[test]
+    fn lonely_root() {
+        let mut net = SimNetwork::new(&[2], &[]);
+
+        let topology = detect_topology(&mut net, ROOT, ROOT + 1).unwrap();
+
+        assert_eq!(topology.len(), 1);
+        assert_eq!(
+            *topology.root(),
+            Node {
+                id: ROOT,
+                parent: None,
+                branch: None,
+            }
+        );
+        assert!(topology.children(ROOT).is_empty());
+    }
+    #[test]
+    fn chain() {
+        // root(0) -- (1)n1(0) -- (0)n2(1) -- (1)n3
+        let mut net = SimNetwork::new(
+            &[2, 2, 2, 2],
+            &[(0, 0, 1, 1), (1, 0, 2, 0), (2, 1, 3, 1)],
+        );
+
+        let topology = detect_topology(&mut net, ROOT, ROOT + 1).unwrap();
+
+        assert_eq!(topology.len(), 4);
+        assert_eq!(net.id(1), Some(2));
+        assert_eq!(net.id(2), Some(3));
+        assert_eq!(net.id(3), Some(4));
+
+        assert_eq!(
+            topology.nodes()[1..],
+            [
+                Node {
+                    id: 2,
+                    parent: Some(1),
+                    branch: Some(0),
+                },
+                Node {
+                    id: 3,
+                    parent: Some(2),
+                    branch: Some(0),
+                },
+                Node {
+                    id: 4,
+                    parent: Some(3),
+                    branch: Some(1),
+                },
+            ]
+        );
+        assert_eq!(topology.parent(4), Some(3));
+        assert_eq!(topology.parent(ROOT), None);
+        assert_eq!(topology.children(2), vec![3]);
+        assert!(topology.children(4).is_empty());
+    }
+    #[test]
+    fn branching_tree() {
+        //          root
+        //      (0)/    \(1)
+        //       n1      n2 (3 branches)
+        //       |    (1)/  \(2)
+        //       n3     n4    n5
+        let mut net = SimNetwork::new(
+            &[2, 2, 3, 2, 2, 2],
+            &[
+                (0, 0, 1, 0),
+                (0, 1, 2, 0),
+                (1, 1, 3, 0),
+                (2, 1, 4, 1),
+                (2, 2, 5, 0),
+            ],
+        );
+
+        let topology = detect_topology(&mut net, ROOT, ROOT + 1).unwrap();
+
+        assert_eq!(topology.len(), 6);
+
+        // Depth-first: the chain on the first branch is numbered first.
+        assert_eq!(net.id(1), Some(2));
+        assert_eq!(net.id(3), Some(3));
+        assert_eq!(net.id(2), Some(4));
+        assert_eq!(net.id(4), Some(5));
+        assert_eq!(net.id(5), Some(6));
+
+        assert_eq!(topology.children(ROOT), vec![2, 4]);
+        assert_eq!(topology.children(2), vec![3]);
+        assert_eq!(topology.children(4), vec![5, 6]);
+
+        assert_eq!(topology.get(4).unwrap().branch, Some(1));
+        assert_eq!(topology.get(5).unwrap().branch, Some(1));
+        assert_eq!(topology.get(6).unwrap().branch, Some(2));
+    }
+    #[test]
+    fn id_overflow() {
+        let mut net = SimNetwork::new(&[2, 2], &[(0, 0, 1, 0)]);
+        net.nodes[0].id = Some(MAX_ID_VAL - 1);
+
+        assert_eq!(
+            detect_topology(&mut net, MAX_ID_VAL - 1, MAX_ID_VAL),
+            Err(DetectionError::IdOverflow)
+        );
+    }
+    #[test]
+    fn no_id_requested() {
+        // root(0) -- (0)n1(1) -- (0)n2
+        let mut net = SimNetwork::new(&[1, 2, 2], &[(0, 0, 1, 0), (1, 1, 2, 0)]);
+        net.nodes[2].ids = 0;
+
+        assert_eq!(
+            detect_topology(&mut net, ROOT, ROOT + 1),
+            Err(DetectionError::NoIdRequested(2))
+        );
+        assert_eq!(net.id(1), Some(2));
+        assert_eq!(net.id(2), None);
+    }
+    #[test]
+    fn assign_failed() {
+        // root(0) -- (0)n1(1) -- (0)n2
+        let mut net = SimNetwork::new(&[1, 2, 2], &[(0, 0, 1, 0), (1, 1, 2, 0)]);
+        net.nodes[2].accepts_id = false;
+
+        assert_eq!(
+            detect_topology(&mut net, ROOT, ROOT + 1),
+            Err(DetectionError::AssignFailed(3))
+        );
+        assert_eq!(net.id(1), Some(2));
+        assert_eq!(net.id(2), None);
+    }
+    #[test]
+    fn multiple_ids_per_node() {
+        // root(0) -- (0)n1(1) -- (0)n2
+        let mut net = SimNetwork::new(&[1, 2, 2], &[(0, 0, 1, 0), (1, 1, 2, 0)]);
+        net.nodes[1].ids = 3;
+
+        let topology = detect_topology(&mut net, ROOT, ROOT + 2).unwrap();
+
+        assert_eq!(net.id(1), Some(3));
+        assert_eq!(net.id(2), Some(6));
+        assert_eq!(topology.children(ROOT), vec![3]);
+        assert_eq!(topology.children(3), vec![6]);
+    }
+    #[test]
+    fn line_abstraction() {
+        let (mut a, b) = ptp_wire();
+        let ptp = Ptp::new(vec![Box::new(b) as Box<PtpLine>]);
+
+        assert_eq!(ptp.branches(), 1);
+        a.pull_low();
+        assert!(a.is_low());
+        a.release();
+        assert!(!a.is_low());
+    }
+}
@@ -0,0 +1,247 @@
+//! Point-to-point (PTP) handshake used to detect the physical neighbours of a node.
+//!
+//! Each PTP line links two neighbours. The lines are pulled-up: a line is low as soon as one of its ends pulls it low.
+//!
+//! The handshake goes as follows:
+//!
+//! * the poking node pulls one of its lines low
+//! * the neighbour detects the low line, pulls it low too and keeps holding it
+//! * the poking node releases the line and reads it back: if it stays low, a neighbour answered
+//! * the neighbour releases the line once it has been given an id
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use physical;
+
+/// Duration a line is kept low when poking a neighbour (in ms).
+const POKE_DURATION: u32 = 1;
+
+/// Abstraction of a point-to-point line.
+pub trait PtpLine {
+    /// Pulls the line low.
+    fn pull_low(&mut self);
+    /// Releases the line: it goes back high unless the neighbour pulls it low.
+    fn release(&mut self);
+    /// Checks if the line is currently low.
+    fn is_low(&self) -> bool;
+}
+
+/// PTP state of a node.
+pub struct Ptp {
+    lines: Vec<Box<PtpLine>>,
+    /// Branch linking the node to its parent.
+    parent: Option<usize>,
+    /// Branch on which the node has been poked and which it is currently holding low.
+    poked: Option<usize>,
+    /// Next branch to explore.
+    next: usize,
+    detected: bool,
+}
+
+impl Ptp {
+    /// Creates the PTP state of a node from its lines.
+    ///
+    /// # Arguments
+    ///
+    /// * `lines` - A `Vec<Box<PtpLine>>` containing one line per branch.
+    pub fn new(lines: Vec<Box<PtpLine>>) -> Ptp {
+        Ptp {
+            lines,
+            parent: None,
+            poked: None,
+            next: 0,
+            detected: false,
+        }
+    }
+    /// Number of branches of the node.
+    pub fn branches(&self) -> usize {
+        self.lines.len()
+    }
+    /// Forgets any previous detection and releases all lines.
+    pub fn reset(&mut self) {
+        for line in self.lines.iter_mut() {
+            line.release();
+        }
+        self.parent = None;
+        self.poked = None;
+        self.next = 0;
+        self.detected = false;
+    }
+    /// Marks the node as the root of the detection (typically the gate).
+    pub fn set_root(&mut self) {
+        self.reset();
+        self.detected = true;
+    }
+    /// Checks if the node has already been detected.
+    pub fn is_detected(&self) -> bool {
+        self.detected
+    }
+    /// Branch linking the node to its parent (`None` for the root or an undetected node).
+    pub fn parent(&self) -> Option<usize> {
+        self.parent
+    }
+    /// Checks if the node is currently answering a poke.
+    pub fn is_poked(&self) -> bool {
+        self.poked.is_some()
+    }
+    /// Watches the lines for a poke.
+    ///
+    /// An undetected node answers the first poke it sees by holding the line low.
+    pub fn poll(&mut self) {
+        if self.detected || self.poked.is_some() {
+            return;
+        }
+        for (branch, line) in self.lines.iter_mut().enumerate() {
+            if line.is_low() {
+                line.pull_low();
+                self.poked = Some(branch);
+                return;
+            }
+        }
+    }
+    /// Returns the next branch to explore (the parent branch is skipped).
+    ///
+    /// Returns `None` once all branches have been explored.
+    pub fn next_branch(&mut self) -> Option<usize> {
+        while self.next < self.lines.len() {
+            let branch = self.next;
+            self.next += 1;
+
+            if Some(branch) != self.parent {
+                return Some(branch);
+            }
+        }
+        None
+    }
+    /// Starts poking the neighbour on `branch`.
+    pub fn begin_poke(&mut self, branch: usize) {
+        self.lines[branch].pull_low();
+    }
+    /// Stops poking the neighbour on `branch`.
+    ///
+    /// Returns `true` if a neighbour answered.
+    pub fn end_poke(&mut self, branch: usize) -> bool {
+        self.lines[branch].release();
+        self.lines[branch].is_low()
+    }
+    /// Pokes the neighbour on `branch`.
+    ///
+    /// *Beware, this function will block for the poke duration, the neighbour needs to `poll` in the meantime.*
+    ///
+    /// # Arguments
+    ///
+    /// * `branch` - The usize branch to poke.
+    /// * `notify` - A `FnMut()` called once the line is low (e.g. to tell the neighbours to `poll`).
+    ///
+    /// Returns `true` if a neighbour answered.
+    pub fn poke<F>(&mut self, branch: usize, mut notify: F) -> bool
+    where
+        F: FnMut(),
+    {
+        self.begin_poke(branch);
+        notify();
+        physical::ms_delay(POKE_DURATION);
+        self.end_poke(branch)
+    }
+    /// Ends the handshake once the poked node has been given an id.
+    ///
+    /// The held line is released and becomes the parent branch.
+    ///
+    /// Returns the parent branch or `None` if the node was not poked.
+    pub fn acknowledge(&mut self) -> Option<usize> {
+        let branch = match self.poked.take() {
+            Some(branch) => branch,
+            None => return None,
+        };
+
+        self.lines[branch].release();
+        self.parent = Some(branch);
+        self.detected = true;
+
+        Some(branch)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use topology::sim::{ptp_wire, SimLine};
+
+    #[test]
+    fn handshake() {
+        let (a, b) = ptp_wire();
+
+        let mut poker = sim_ptp(vec![a, SimLine::open()]);
+        poker.set_root();
+        let mut neighbour = sim_ptp(vec![SimLine::open(), b]);
+
+        neighbour.poll();
+        assert!(!neighbour.is_poked());
+
+        poker.begin_poke(0);
+        neighbour.poll();
+        assert!(neighbour.is_poked());
+        assert!(poker.end_poke(0));
+
+        assert_eq!(neighbour.acknowledge(), Some(1));
+        assert!(neighbour.is_detected());
+        assert_eq!(neighbour.parent(), Some(1));
+        assert!(!neighbour.is_poked());
+
+        // Once detected, the neighbour stops answering.
+        poker.begin_poke(0);
+        neighbour.poll();
+        assert!(!neighbour.is_poked());
+        assert!(!poker.end_poke(0));
+    }
+    #[test]
+    fn nobody_answers() {
+        let mut poker = sim_ptp(vec![SimLine::open()]);
+        poker.set_root();
+
+        poker.begin_poke(0);
+        assert!(!poker.end_poke(0));
+        assert!(!poker.poke(0, || {}));
+    }
+    #[test]
+    fn blocking_poke() {
+        let (a, b) = ptp_wire();
+
+        let mut poker = sim_ptp(vec![a]);
+        poker.set_root();
+        let mut neighbour = sim_ptp(vec![b]);
+
+        assert!(poker.poke(0, || neighbour.poll()));
+        assert!(neighbour.is_poked());
+    }
+    #[test]
+    fn skip_parent_branch() {
+        let (a, b) = ptp_wire();
+
+        let mut poker = sim_ptp(vec![a]);
+        poker.set_root();
+        let mut neighbour = sim_ptp(vec![SimLine::open(), b, SimLine::open()]);
+
+        poker.begin_poke(0);
+        neighbour.poll();
+        poker.end_poke(0);
+        neighbour.acknowledge();
+
+        assert_eq!(neighbour.next_branch(), Some(0));
+        assert_eq!(neighbour.next_branch(), Some(2));
+        assert_eq!(neighbour.next_branch(), None);
+
+        neighbour.reset();
+        assert!(!neighbour.is_detected());
+        assert_eq!(neighbour.next_branch(), Some(0));
+    }
+    pub fn sim_ptp(lines: Vec<SimLine>) -> Ptp {
+        Ptp::new(
+            lines
+                .into_iter()
+                .map(|line| Box::new(line) as Box<PtpLine>)
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,51 @@
+//! Simulated PTP lines
+//!
+//! Allows to run the topology detection on the host without any board.
+
+use std::rc::Rc;
+use std::cell::Cell;
+
+use super::PtpLine;
+
+/// Creates a simulated PTP wire and returns its two ends.
+pub fn ptp_wire() -> (SimLine, SimLine) {
+    let wire = Rc::new([Cell::new(false), Cell::new(false)]);
+
+    let a = SimLine {
+        wire: wire.clone(),
+        end: 0,
+    };
+    let b = SimLine {
+        wire: wire.clone(),
+        end: 1,
+    };
+
+    (a, b)
+}
+
+/// One end of a simulated PTP wire.
+pub struct SimLine {
+    /// Whether each end of the wire is pulling it low.
+    wire: Rc<[Cell<bool>; 2]>,
+    end: usize,
+}
+
+impl SimLine {
+    /// Creates a line connected to nobody.
+    pub fn open() -> SimLine {
+        let (line, _) = ptp_wire();
+        line
+    }
+}
+
+impl PtpLine for SimLine {
+    fn pull_low(&mut self) {
+        self.wire[self.end].set(true);
+    }
+    fn release(&mut self) {
+        self.wire[self.end].set(false);
+    }
+    fn is_low(&self) -> bool {
+        self.wire[0].get() || self.wire[1].get()
+    }
+}
@@ -0,0 +1,341 @@
+//! Segmented transfers
+//!
+//! Payloads larger than a frame are split into `Command::Fragment` messages (see `Core::send_large`) and rebuilt by the receiving `Core`. Each fragment carries the original command, the transfer number, its index and the number of fragments before its chunk of data.
+
+use {error, Command, Message};
+
+use command;
+
+use alloc::String;
+use alloc::vec::Vec;
+
+/// Max size of the data of a transfer (in bytes).
+pub const MAX_TRANSFER_SIZE: usize = 4096;
+/// Time without any fragment before an incomplete transfer is dropped (in µs).
+pub const TRANSFER_TIMEOUT: u64 = 500_000;
+/// Transfers rebuilt at the same time (one per source).
+const MAX_TRANSFERS: usize = 4;
+
+const FRAGMENT_HEADER_SIZE: usize = 4;
+/// The data size is sent as a single byte.
+const CHUNK_SIZE: usize = 255 - FRAGMENT_HEADER_SIZE;
+const MAX_FRAGMENTS: usize = (MAX_TRANSFER_SIZE + CHUNK_SIZE - 1) / CHUNK_SIZE;
+
+/// Error raised by the reassembly of a transfer (see `Core::set_error_callback`).
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferError {
+    /// The transfer from the given source exceeds `MAX_TRANSFER_SIZE`.
+    TooLarge(u16),
+    /// The transfer from the given source has been dropped before its end (missing fragments).
+    Incomplete(u16),
+    /// A fragment from the given source is malformed or does not match its transfer.
+    InvalidFragment(u16),
+}
+
+impl error::Error for TransferError {
+    fn description(&self) -> String {
+        match *self {
+            TransferError::TooLarge(source) => {
+                format!("Transfer from module {} over {} bytes", source, MAX_TRANSFER_SIZE)
+            }
+            TransferError::Incomplete(source) => {
+                format!("Incomplete transfer from module {}", source)
+            }
+            TransferError::InvalidFragment(source) => {
+                format!("Invalid fragment from module {}", source)
+            }
+        }
+    }
+}
+
+/// Split a `Message` into the fragments of the transfer number `transfer`.
+///
+/// The fragments keep the target of the `Message`. Even an empty `Message` gives one fragment.
+pub fn split(msg: &Message, transfer: u8) -> Vec<Message> {
+    let chunks: Vec<&[u8]> = if msg.data.is_empty() {
+        vec![&msg.data[..]]
+    } else {
+        msg.data.chunks(CHUNK_SIZE).collect()
+    };
+    let count = chunks.len();
+    assert!(count <= MAX_FRAGMENTS);
+
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut data = vec![msg.header.command as u8, transfer, index as u8, count as u8];
+            data.extend_from_slice(chunk);
+
+            let mut fragment = msg.clone();
+            fragment.header.command = Command::Fragment;
+            fragment.header.data_size = data.len();
+            fragment.data = data;
+            fragment
+        })
+        .collect()
+}
+
+/// Transfer being rebuilt.
+struct Transfer {
+    source: u16,
+    number: u8,
+    command: Command,
+    chunks: Vec<Option<Vec<u8>>>,
+    /// Reception time of its last fragment (in µs).
+    last: u64,
+}
+
+/// Reassembly buffers of the incoming transfers, one per source.
+pub struct Reassembler {
+    transfers: Vec<Transfer>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler {
+            transfers: Vec::new(),
+        }
+    }
+    /// Add a `Command::Fragment` message received at `now` (in µs) to its transfer.
+    ///
+    /// Returns the rebuilt `Message` once its last fragment arrived. Errors are given through `errors`: a transfer may be dropped in favor of a new one.
+    pub fn push(
+        &mut self,
+        fragment: &Message,
+        now: u64,
+        errors: &mut Vec<TransferError>,
+    ) -> Option<Message> {
+        let source = fragment.header.source;
+        if fragment.data.len() < FRAGMENT_HEADER_SIZE {
+            errors.push(TransferError::InvalidFragment(source));
+            return None;
+        }
+        let (command, number, index, count) = (
+            fragment.data[0],
+            fragment.data[1],
+            fragment.data[2] as usize,
+            fragment.data[3] as usize,
+        );
+        let command = match command::from_u8(command) {
+            Some(command) if !command.is_protocol() => command,
+            _ => {
+                errors.push(TransferError::InvalidFragment(source));
+                return None;
+            }
+        };
+        if count > MAX_FRAGMENTS {
+            errors.push(TransferError::TooLarge(source));
+            return None;
+        }
+        if index >= count {
+            errors.push(TransferError::InvalidFragment(source));
+            return None;
+        }
+
+        // A new transfer replaces the unfinished one of the same source.
+        let current = self.transfers.iter().position(|t| t.source == source);
+        let pos = match current {
+            Some(pos) => {
+                let same = self.transfers[pos].number == number
+                    && self.transfers[pos].chunks.len() == count;
+                if same {
+                    Some(pos)
+                } else {
+                    errors.push(TransferError::Incomplete(source));
+                    self.transfers.remove(pos);
+                    None
+                }
+            }
+            None => None,
+        };
+        let pos = match pos {
+            Some(pos) => pos,
+            None => {
+                if self.transfers.len() == MAX_TRANSFERS {
+                    // Drop the stalest one.
+                    let stalest = self.transfers
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|&(_, t)| t.last)
+                        .map(|(i, _)| i)
+                        .unwrap();
+                    let dropped = self.transfers.remove(stalest);
+                    errors.push(TransferError::Incomplete(dropped.source));
+                }
+                self.transfers.push(Transfer {
+                    source,
+                    number,
+                    command,
+                    chunks: vec![None; count],
+                    last: now,
+                });
+                self.transfers.len() - 1
+            }
+        };
+
+        {
+            let transfer = &mut self.transfers[pos];
+            // Re-sent fragments simply replace the previous ones.
+            transfer.chunks[index] = Some(fragment.data[FRAGMENT_HEADER_SIZE..].to_vec());
+            transfer.last = now;
+            if transfer.chunks.iter().any(|chunk| chunk.is_none()) {
+                return None;
+            }
+        }
+
+        let transfer = self.transfers.remove(pos);
+        let mut data = Vec::new();
+        for chunk in transfer.chunks.into_iter() {
+            data.extend(chunk.unwrap());
+        }
+
+        let mut msg = fragment.clone();
+        msg.header.command = transfer.command;
+        msg.header.data_size = data.len();
+        msg.data = data;
+        Some(msg)
+    }
+    /// Drop the transfers idle for too long at `now` (in µs).
+    pub fn expire(&mut self, now: u64, errors: &mut Vec<TransferError>) {
+        let mut i = 0;
+        while i < self.transfers.len() {
+            if now.saturating_sub(self.transfers[i].last) > TRANSFER_TIMEOUT {
+                let expired = self.transfers.remove(i);
+                errors.push(TransferError::Incomplete(expired.source));
+            } else {
+                i += 1;
+            }
+        }
+    }
+    /// Number of transfers being rebuilt.
+    pub fn len(&self) -> usize {
+        self.transfers.len()
+    }
+}
+
+/// Transfers of a `Core`: the numbering of the ones it sends, the reassembly of the ones it receives.
+pub struct Transfers {
+    /// Number of our next transfer.
+    next: u8,
+    reassembler: Reassembler,
+}
+
+impl Transfers {
+    pub fn new() -> Transfers {
+        Transfers {
+            next: 0,
+            reassembler: Reassembler::new(),
+        }
+    }
+    /// Split a `Message` into the fragments of our next transfer (see `split`).
+    pub fn split(&mut self, msg: &Message) -> Vec<Message> {
+        let number = self.next;
+        self.next = self.next.wrapping_add(1);
+        split(msg, number)
+    }
+    /// Handles a `Command::Fragment` received at `now` (in µs), see `Reassembler::push`.
+    pub fn handle(
+        &mut self,
+        fragment: &Message,
+        now: u64,
+        errors: &mut Vec<TransferError>,
+    ) -> Option<Message> {
+        self.reassembler.push(fragment, now, errors)
+    }
+    /// Drop the transfers idle for too long at `now` (in µs).
+    pub fn expire(&mut self, now: u64, errors: &mut Vec<TransferError>) {
+        self.reassembler.expire(now, errors);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use msg::tests::rand_command;
+
+    fn large_msg(size: usize) -> Message {
+        let data = (0..size).map(|i| i as u8).collect();
+        let mut msg = Message::id(42, rand_command(), &data);
+        msg.header.source = 2;
+        msg
+    }
+
+    #[test]
+    fn split_and_rebuild() {
+        let msg = large_msg(1000);
+        let fragments = split(&msg, 7);
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|f| f.data.len() <= 255));
+        assert!(fragments.iter().all(|f| f.header.command == Command::Fragment));
+
+        let mut reassembler = Reassembler::new();
+        let mut errors = Vec::new();
+        // Out of order
+        for fragment in fragments.iter().rev().skip(1) {
+            assert_eq!(reassembler.push(fragment, 0, &mut errors), None);
+        }
+        assert_eq!(reassembler.push(&fragments[3], 0, &mut errors), Some(msg));
+        assert!(errors.is_empty());
+        assert_eq!(reassembler.len(), 0);
+
+        let empty = large_msg(0);
+        let fragments = split(&empty, 8);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(reassembler.push(&fragments[0], 0, &mut errors), Some(empty));
+    }
+    #[test]
+    fn drop_transfers() {
+        let mut reassembler = Reassembler::new();
+        let mut errors = Vec::new();
+
+        // Restarted transfer
+        let fragments = split(&large_msg(600), 1);
+        reassembler.push(&fragments[0], 0, &mut errors);
+        let msg = large_msg(300);
+        let restart = split(&msg, 2);
+        assert_eq!(reassembler.push(&restart[0], 0, &mut errors), None);
+        assert_eq!(reassembler.push(&restart[1], 0, &mut errors), Some(msg));
+        assert_eq!(errors, vec![TransferError::Incomplete(2)]);
+
+        // Expired transfer
+        errors.clear();
+        reassembler.push(&fragments[0], 1_000, &mut errors);
+        reassembler.expire(1_000 + TRANSFER_TIMEOUT, &mut errors);
+        assert!(errors.is_empty());
+        reassembler.expire(1_001 + TRANSFER_TIMEOUT, &mut errors);
+        assert_eq!(errors, vec![TransferError::Incomplete(2)]);
+        assert_eq!(reassembler.len(), 0);
+
+        // Oversized and malformed fragments
+        errors.clear();
+        let mut fragment = fragments[0].clone();
+        fragment.data[3] = MAX_FRAGMENTS as u8 + 1;
+        assert_eq!(reassembler.push(&fragment, 0, &mut errors), None);
+        fragment.data[2] = 0xFF;
+        fragment.data[3] = 2;
+        assert_eq!(reassembler.push(&fragment, 0, &mut errors), None);
+        assert_eq!(
+            errors,
+            vec![TransferError::TooLarge(2), TransferError::InvalidFragment(2)]
+        );
+        assert_eq!(reassembler.len(), 0);
+    }
+    #[test]
+    fn numbered_transfers() {
+        let mut transfers = Transfers::new();
+        let mut errors = Vec::new();
+        let msg = large_msg(300);
+
+        let first = transfers.split(&msg);
+        let second = transfers.split(&msg);
+        assert_eq!((first[0].data[1], second[0].data[1]), (0, 1));
+
+        assert_eq!(transfers.handle(&first[0], 0, &mut errors), None);
+        // The second transfer replaces the first one.
+        assert_eq!(transfers.handle(&second[0], 0, &mut errors), None);
+        assert_eq!(transfers.handle(&second[1], 0, &mut errors), Some(msg));
+        assert_eq!(errors, vec![TransferError::Incomplete(2)]);
+    }
+}